@@ -1,49 +1,66 @@
 use crate::cli::print_to_dashboard::{print_to_dashboard, print_to_dashboard_with_coordinates};
-use crate::encryption::{self, decrypt_data, encrypt_data};
-use crate::supabase::{check_os_details, create_config_entry, create_user_entry};
-use chrono::Utc; // For timestamp
+use crate::config::config_dir;
+use crate::encryption::{self, decrypt_with_key, encrypt_with_key};
+use crate::storage;
+use crate::supabase::{check_os_details, client, Session, SupabaseError};
+use chrono::{DateTime, Local, Utc}; // For timestamp
 use colored::Colorize;
 use dirs::home_dir;
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use openssl::rand::rand_bytes;
 use std::error::Error;
-use std::fs;
-use std::fs::File;
+use std::fs::{self, DirBuilder, OpenOptions, Permissions};
 use std::io::Write;
-use std::path::PathBuf;
-
-#[derive(Serialize, Deserialize)]
-struct Session {
-    access_token: String,
-    email: String,
-    user_id: String,    // Store the user ID for later reference
-    login_time: String, // Timestamp of login
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+// The session lives in the config directory, encrypted under a key of its own rather than the
+// backup keys, so a leaked session file says nothing about backup ciphertext and vice versa
+const SESSION_FILE: &str = "session.bin"; // Using binary format for storage
+const SESSION_KEY_FILE: &str = "session.key";
+const LEGACY_SESSION_FILE: &str = "continue.bin"; // Written to the home directory by older versions
+
+fn session_file_path() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(config_dir()?.join(SESSION_FILE))
 }
 
-const SESSION_FILE: &str = "continue.bin"; // Using binary format for storage
+// Only the owner may read or write the session and its key
+fn write_private(path: &Path, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+    }
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // `mode` only applies to new files, so tighten files left by older versions too
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(data)?;
+    Ok(())
+}
 
-fn session_file_path() -> PathBuf {
-    let mut path = home_dir().expect("Unable to determine home directory");
-    path.push(SESSION_FILE);
-    path
+// The random key the session file is encrypted with, created on first use
+fn session_key() -> Result<Vec<u8>, Box<dyn Error>> {
+    let path = config_dir()?.join(SESSION_KEY_FILE);
+    if path.exists() {
+        return Ok(fs::read(path)?);
+    }
+    let mut key = vec![0u8; 32];
+    rand_bytes(&mut key)?;
+    write_private(&path, &key)?;
+    Ok(key)
 }
 
 /// Save the session to a file, encrypted for security
-fn save_session(session: &Session) -> Result<(), Box<dyn Error>> {
-    let session_path = session_file_path();
+pub(crate) fn save_session(session: &Session) -> Result<(), Box<dyn Error>> {
     let session_data = bincode::serialize(&session)?; // Serialize session to binary format
-    let encrypted_data = encrypt_data(&session_data)?; // Encrypt the session data
-    let mut file = File::create(session_path)?;
-    file.write_all(&encrypted_data)?; // Write encrypted data to binary file
+    let encrypted_data = encrypt_with_key(&session_key()?, &session_data)?; // Encrypt the session data
+    write_private(&session_file_path()?, &encrypted_data)?;
     Ok(())
 }
 
 /// Load the session from a file, decrypting it
-fn load_session() -> Result<Session, Box<dyn Error>> {
-    let session_path = session_file_path();
+pub(crate) fn load_session() -> Result<Session, Box<dyn Error>> {
+    let session_path = session_file_path()?;
     if session_path.exists() {
         let encrypted_data = fs::read(session_path)?; // Read encrypted data
-        let decrypted_data = decrypt_data(&encrypted_data)?; // Decrypt the session data
+        let decrypted_data = decrypt_with_key(&session_key()?, &encrypted_data)?; // Decrypt the session data
         let session: Session = bincode::deserialize(&decrypted_data)?; // Deserialize from binary format
         Ok(session)
     } else {
@@ -52,60 +69,65 @@ fn load_session() -> Result<Session, Box<dyn Error>> {
 }
 
 /// Clear the session by deleting the session file
-fn clear_session() -> Result<(), Box<dyn Error>> {
-    let session_path = session_file_path();
+pub(crate) fn clear_session() -> Result<(), Box<dyn Error>> {
+    let session_path = session_file_path()?;
     if session_path.exists() {
         fs::remove_file(session_path)?;
     }
+    remove_legacy_session()
+}
+
+// Sessions of older versions were encrypted with the backup key and readable by anyone with home
+// directory access. They are not migrated; logging in again replaces them.
+fn remove_legacy_session() -> Result<(), Box<dyn Error>> {
+    if let Some(home) = home_dir() {
+        let legacy_path = home.join(LEGACY_SESSION_FILE);
+        if legacy_path.exists() {
+            fs::remove_file(legacy_path)?;
+        }
+    }
     Ok(())
 }
 
 /// Check if the session exists and return status
 pub fn is_logged_in() -> bool {
-    session_file_path().exists()
+    session_file_path().map(|path| path.exists()).unwrap_or(false)
+}
+
+/// Whether backups are available: logged in, or stored somewhere that needs no account
+pub fn can_back_up() -> bool {
+    !storage::needs_login() || is_logged_in()
+}
+
+/// The access token of the session, refreshed first if it is about to expire.
+/// A session that can't be read or whose refresh token was rejected is cleared, so the user is logged out.
+pub async fn access_token() -> Result<String, SupabaseError> {
+    client()?.access_token().await
 }
 
 /// Perform login and save the session
 pub async fn login(email: &str, password: &str) -> Result<(), Box<dyn Error>> {
-    let client = Client::new();
-    let supabase_url = std::env::var("SUPABASE_URL")?;
-    let supabase_key = std::env::var("SUPABASE_KEY")?;
-    let login_url = format!("{}/auth/v1/token?grant_type=password", supabase_url);
-
-    let response = client
-        .post(&login_url)
-        .header("apikey", supabase_key)
-        .json(&serde_json::json!({
-            "email": email,
-            "password": password
-        }))
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        let data: serde_json::Value = response.json().await?;
-        let access_token = data["access_token"]
-            .as_str()
-            .ok_or("Failed to get access token")?;
-        let user_id = data["user"]["id"].as_str().ok_or("Failed to get user ID")?;
-        let login_time = Utc::now().to_rfc3339();
-
-        let session = Session {
-            access_token: access_token.to_string(),
-            email: email.to_string(),
-            user_id: user_id.to_string(),
-            login_time,
-        };
-        println!("{}", session.access_token.to_string());
-        // Save the session securely
-        save_session(&session)?;
-
-        let (current_os_name, current_os_version) = crate::config::get_os_details()?;
-        check_os_details(user_id, &current_os_name, &current_os_version).await?;
-
-        print_to_dashboard(&format!("Successfully logged in as {}", email).green());
-    } else {
-        print_to_dashboard(&format!("Login failed: {}", response.text().await?).red());
+    let client = client()?;
+    match client.sign_in(email, password).await {
+        Ok(tokens) => {
+            let session = Session::new(email, tokens);
+            let user_id = session.user_id.clone();
+            // Save the session securely
+            client.set_session(session)?;
+            remove_legacy_session()?;
+
+            // Unlock the backup keys right away, so backups can run for the rest of this session.
+            // Stay logged in if that fails: `key recover` needs the session to replace a forgotten passphrase.
+            if let Err(e) = encryption::unlock().await {
+                print_to_dashboard(&format!("Backups stay locked: {}", e).yellow());
+            }
+
+            let (current_os_name, current_os_version) = crate::config::get_os_details()?;
+            check_os_details(&user_id, &current_os_name, &current_os_version).await?;
+
+            print_to_dashboard(&format!("Successfully logged in as {}", email).green());
+        }
+        Err(e) => print_to_dashboard(&format!("Login failed: {}", e).red()),
     }
 
     Ok(())
@@ -113,7 +135,7 @@ pub async fn login(email: &str, password: &str) -> Result<(), Box<dyn Error>> {
 
 /// Log out by clearing the session
 pub fn logout() -> Result<(), Box<dyn Error>> {
-    clear_session()?;
+    client()?.sign_out()?;
     print_to_dashboard("Successfully logged out.".green().to_string().as_str());
     Ok(())
 }
@@ -136,7 +158,13 @@ pub fn session_status() -> Result<(), Box<dyn Error>> {
             50,
             11,
         );
-    
+        let expires_at = DateTime::<Utc>::from_timestamp(session.expires_at, 0).unwrap_or_default();
+        print_to_dashboard_with_coordinates(
+            &format!("Token expires: {} (refreshed automatically)", expires_at.with_timezone(&Local).format("%Y-%m-%d %H:%M")).green(),
+            50,
+            12,
+        );
+
     } else {
         print_to_dashboard(&"Not logged in.".red());
     }
@@ -145,28 +173,9 @@ pub fn session_status() -> Result<(), Box<dyn Error>> {
 
 /// Request a password reset link
 pub async fn password_reset(email: &str) -> Result<(), Box<dyn Error>> {
-    let client = Client::new();
-    let supabase_url = std::env::var("SUPABASE_URL")?;
-    let supabase_key = std::env::var("SUPABASE_KEY")?;
-    let reset_url = format!("{}/auth/v1/recover", supabase_url);
-
-    let response = client
-        .post(&reset_url)
-        .header("apikey", supabase_key)
-        .json(&serde_json::json!({ "email": email }))
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        print_to_dashboard(&format!("Password reset link sent to {}", email).green());
-    } else {
-        print_to_dashboard(
-            &format!(
-                "Failed to send password reset link: {}",
-                response.text().await?
-            )
-            .red(),
-        );
+    match client()?.recover_password(email).await {
+        Ok(()) => print_to_dashboard(&format!("Password reset link sent to {}", email).green()),
+        Err(e) => print_to_dashboard(&format!("Failed to send password reset link: {}", e).red()),
     }
 
     Ok(())
@@ -174,35 +183,14 @@ pub async fn password_reset(email: &str) -> Result<(), Box<dyn Error>> {
 
 /// Sign up a new user using Supabase Auth
 pub async fn sign_up(email: &str, password: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-    let supabase_url = std::env::var("SUPABASE_URL")?;
-    let supabase_key = std::env::var("SUPABASE_KEY")?;
-    let sign_up_url = format!("{}/auth/v1/signup", supabase_url);
-
-    let response = client
-        .post(&sign_up_url)
-        .header("apikey", supabase_key)
-        .json(&serde_json::json!({
-            "email": email,
-            "password": password
-        }))
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        // Extract user information from response
-        let data: serde_json::Value = response.json().await?;
-        let user_id = data["user"]["id"].as_str().ok_or("Failed to get user id")?;
-
-        print_to_dashboard(
+    match client()?.sign_up(email, password).await {
+        Ok(()) => print_to_dashboard(
             "Successfully signed up. Please log in."
                 .green()
                 .to_string()
                 .as_str(),
-        );
-    } else {
-        let error_message = response.text().await?;
-        print_to_dashboard(&format!("Sign-up failed: {}", error_message).red());
+        ),
+        Err(e) => print_to_dashboard(&format!("Sign-up failed: {}", e).red()),
     }
 
     Ok(())
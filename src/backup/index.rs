@@ -0,0 +1,103 @@
+use crate::config::data_dir;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+const INDEX_FILE: &str = "index.json";
+
+/// Size, modification time and hash of a file as of the last backup
+#[derive(Serialize, Deserialize, Clone)]
+struct IndexEntry {
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    sha256: String,
+}
+
+/// Local record of every file hashed by previous backups, so unchanged files need not be read again
+#[derive(Serialize, Deserialize, Default)]
+pub struct LocalIndex {
+    files: HashMap<String, IndexEntry>,
+}
+
+impl LocalIndex {
+    /// Load the index, starting empty when it is missing or unreadable
+    pub fn load() -> Self {
+        let load = || -> Result<Self, Box<dyn Error>> { Ok(serde_json::from_slice(&fs::read(index_path()?)?)?) };
+        load().unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = index_path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    /// The recorded hash of a file, if its size and modification time are unchanged since it was hashed
+    pub fn cached_hash(&self, path: &str, metadata: &Metadata) -> Option<&str> {
+        self.files
+            .get(path)
+            .filter(|entry| {
+                entry.size == metadata.len()
+                    && entry.mtime == metadata.mtime()
+                    && entry.mtime_nsec == metadata.mtime_nsec()
+            })
+            .map(|entry| entry.sha256.as_str())
+    }
+
+    pub fn update(&mut self, path: &str, metadata: &Metadata, sha256: &str) {
+        self.files.insert(
+            path.to_string(),
+            IndexEntry {
+                size: metadata.len(),
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+                sha256: sha256.to_string(),
+            },
+        );
+    }
+}
+
+fn index_path() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(data_dir()?.join(INDEX_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn cached_hash_needs_the_same_size_and_mtime() {
+        let scratch = storage::use_test_storage().join("index");
+        fs::create_dir_all(&scratch).unwrap();
+        let path = scratch.join("app.conf");
+        let path_str = path.to_str().unwrap();
+        fs::write(&path, b"port = 80\n").unwrap();
+
+        let mut index = LocalIndex::default();
+        assert_eq!(index.cached_hash(path_str, &fs::metadata(&path).unwrap()), None);
+        index.update(path_str, &fs::metadata(&path).unwrap(), "hash");
+        assert_eq!(index.cached_hash(path_str, &fs::metadata(&path).unwrap()), Some("hash"));
+        assert_eq!(index.cached_hash("/etc/other.conf", &fs::metadata(&path).unwrap()), None);
+
+        // Same size, touched since
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+        assert_eq!(index.cached_hash(path_str, &fs::metadata(&path).unwrap()), None);
+
+        // Same mtime, different size
+        index.update(path_str, &fs::metadata(&path).unwrap(), "hash");
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, b"port = 8080\n").unwrap();
+        fs::File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        assert_eq!(index.cached_hash(path_str, &fs::metadata(&path).unwrap()), None);
+    }
+}
@@ -1,10 +1,15 @@
-use crate::encryption;
+pub mod index;
+
+use crate::chunking::{ChunkNamer, ChunkUploader};
+use crate::encryption::{self, DataKey};
+use crate::snapshot::{self, FileEntry, Manifest};
+use crate::storage;
 use crate::supabase;
+use index::LocalIndex;
 use crate::logging::{log_progress, write_log};
-use crate::config::get_config_files; // Updated config loading
+use crate::config::{get_compression_settings, get_config_files, get_opaque_names}; // Updated config loading
 use std::fs::{self, File};
 use std::io::{BufWriter, Write};
-use std::path::Path;
 use std::error::Error;
 use std::process::Command;
 
@@ -13,6 +18,7 @@ pub struct BackupState {
     pub completed_files: usize,
     pub total_size: u64,
     pub processed_size: u64,
+    pub failed_files: usize,
 }
 
 impl BackupState {
@@ -22,6 +28,7 @@ impl BackupState {
             completed_files: 0,
             total_size,
             processed_size: 0,
+            failed_files: 0,
         }
     }
 
@@ -31,7 +38,16 @@ impl BackupState {
         log_progress(self);
     }
 
+    pub fn record_failure(&mut self, file_size: u64) {
+        self.failed_files += 1;
+        self.update_progress(file_size);
+    }
+
     pub fn progress_percentage(&self) -> f64 {
+        // Fall back to counting files when sizes are unknown (e.g. before a restore has downloaded anything)
+        if self.total_size == 0 {
+            return (self.completed_files as f64 / self.total_files.max(1) as f64) * 100.0;
+        }
         (self.processed_size as f64 / self.total_size as f64) * 100.0
     }
 }
@@ -40,15 +56,43 @@ impl BackupState {
 pub async fn backup_system() -> Result<(), Box<dyn Error>> {
     write_log("Starting system backup...");
 
+    // Every object of this run lives under its own snapshot prefix
+    let mut manifest = Manifest::new()?;
+
+    // Unchanged files are not uploaded again but point at the objects of the previous snapshot
+    let previous = previous_manifest().await?;
+    let mut index = LocalIndex::load();
+
+    // Opaque names keep the naming key of the previous snapshot, so its chunks can still be shared
+    manifest.name_key = if get_opaque_names()? {
+        match previous.as_ref().and_then(|manifest| manifest.name_key.clone()) {
+            Some(name_key) => Some(name_key),
+            None => Some(encryption::generate_wrapped_key()?),
+        }
+    } else {
+        None
+    };
+    let namer = ChunkNamer::for_manifest(&manifest)?;
+
+    // After switching between hashed and opaque names, the previous chunks are stored under other names
+    let reusable = previous
+        .as_ref()
+        .filter(|previous| previous.name_key.as_ref().map(|key| &key.id) == manifest.name_key.as_ref().map(|key| &key.id));
+
+    // Chunks already in storage are never uploaded twice, whichever file or snapshot they came from
+    let compression = get_compression_settings()?;
+    let mut uploader = ChunkUploader::new(previous.as_ref(), reusable.is_some(), compression, DataKey::generate()?, namer);
+
     // Backup system configuration files
     let config_files = get_config_files()?; // Get the list of configuration files from config.rs
     let total_size: u64 = config_files.iter().map(|file| get_file_size(file)).sum();
     let mut state = BackupState::new(config_files.len(), total_size);
 
     for file in config_files {
-        if Path::new(&file).exists() {
+        if fs::symlink_metadata(&file).is_ok() {
             let file_size = get_file_size(&file);
-            backup_file(&file, &mut state).await?;
+            let entry = backup_file(&file, reusable, &mut index, &mut uploader).await?;
+            manifest.files.push(entry);
             state.update_progress(file_size);
         } else {
             write_log(&format!("File not found: {}", file));
@@ -56,29 +100,102 @@ pub async fn backup_system() -> Result<(), Box<dyn Error>> {
     }
 
     // Backup list of installed packages
-    backup_installed_packages().await?;
+    manifest.packages = Some(backup_installed_packages(reusable, &mut uploader).await?);
+
+    // Chunks shared with earlier snapshots keep their keys, so only the keys of referenced chunks are carried forward
+    let (chunk_keys, data_keys) = uploader.chunk_keys(&manifest)?;
+    manifest.chunk_keys = chunk_keys;
+    manifest.data_keys = data_keys;
+
+    // Keys left behind are stored on their own, so a chunk they encrypted can still be shared later
+    for dropped in previous.iter().flat_map(|previous| &previous.data_keys) {
+        if !manifest.data_keys.iter().any(|key| key.id == dropped.id) {
+            snapshot::record_data_key(dropped).await?;
+        }
+    }
+
+    // Upload the manifest last so a snapshot only becomes visible once its data is in place
+    let manifest_size = snapshot::upload_manifest(&manifest, &compression).await?;
+
+    // Supabase lists snapshots from the backups table, while other backends find them from the manifest.
+    // A manifest whose row was refused would never be listed, so it is removed again.
+    if storage::needs_login() {
+        let stored = supabase::store_metadata_in_db(&manifest, uploader.uploaded_bytes() + manifest_size).await;
+        if let Err(e) = stored {
+            if e.is_refused() {
+                let removed = storage::backend()?.delete(&snapshot::manifest_key(&manifest.snapshot_id)).await;
+                if let Err(e) = removed {
+                    write_log(&format!("Failed to remove the unlisted manifest: {}", e));
+                }
+            }
+            return Err(e.into());
+        }
+    }
+    index.save()?;
 
     write_log("Backup completed successfully.");
     Ok(())
 }
 
-// Backup a specific configuration file
-async fn backup_file(file_path: &str, _state: &mut BackupState) -> Result<(), Box<dyn Error>> {
-    let file_data = fs::read(file_path)?;
-    let encrypted_data = encryption::encrypt_data(&file_data)?;
+// The latest snapshot, if there is one. A snapshot that exists but can't be read stops the backup,
+// because chunks already in storage could then be reused without the data keys needed to read them.
+async fn previous_manifest() -> Result<Option<Manifest>, Box<dyn Error>> {
+    let backups = snapshot::fetch_snapshots().await?;
+    match backups.first() {
+        Some(latest) => Ok(Some(snapshot::fetch_manifest(&latest.snapshot_id).await?)),
+        None => Ok(None),
+    }
+}
 
-    supabase::upload_file(file_path, &encrypted_data).await?;
+// Backup a specific configuration file, recording where it came from
+async fn backup_file(
+    file_path: &str,
+    previous: Option<&Manifest>,
+    index: &mut LocalIndex,
+    uploader: &mut ChunkUploader,
+) -> Result<FileEntry, Box<dyn Error>> {
+    // Symlinks are recorded as links rather than followed
+    let metadata = fs::symlink_metadata(file_path)?;
+    if metadata.file_type().is_symlink() {
+        let target = fs::read_link(file_path)?;
+        return Ok(FileEntry::new_symlink(file_path, &metadata, &target.to_string_lossy()));
+    }
 
-    Ok(())
+    // Only read and hash files whose size or modification time changed since the last run
+    let mut file_data = None;
+    let sha256 = match index.cached_hash(file_path, &metadata) {
+        Some(sha256) => sha256.to_string(),
+        None => snapshot::content_hash(file_data.insert(fs::read(file_path)?)),
+    };
+    index.update(file_path, &metadata, &sha256);
+
+    let unchanged = previous
+        .and_then(|manifest| manifest.file(file_path))
+        .filter(|entry| entry.sha256 == sha256 && entry.symlink_target.is_none());
+    if let Some(entry) = unchanged {
+        write_log(&format!("Unchanged, reusing previous upload: {}", file_path));
+        return Ok(FileEntry::with_hash(file_path, &metadata, sha256, entry.chunks.clone()));
+    }
+
+    let file_data = match file_data {
+        Some(file_data) => file_data,
+        None => fs::read(file_path)?,
+    };
+    let chunks = uploader.upload(&file_data).await?;
+
+    Ok(FileEntry::new(file_path, &metadata, &file_data, chunks))
 }
 
 // Get file size for progress tracking
 fn get_file_size(file_path: &str) -> u64 {
-    fs::metadata(file_path).map(|meta| meta.len()).unwrap_or(0)
+    fs::symlink_metadata(file_path).map(|meta| meta.len()).unwrap_or(0)
 }
 
 // Backup installed package list for Ubuntu (dpkg-based systems)
-async fn backup_installed_packages() -> Result<(), Box<dyn Error>> {
+async fn backup_installed_packages(
+    previous: Option<&Manifest>,
+    uploader: &mut ChunkUploader,
+) -> Result<FileEntry, Box<dyn Error>> {
     write_log("Backing up installed packages...");
 
     let output = Command::new("dpkg")
@@ -89,11 +206,22 @@ async fn backup_installed_packages() -> Result<(), Box<dyn Error>> {
     let packages_file_path = "/tmp/installed_packages.txt";
     let mut file = BufWriter::new(File::create(packages_file_path)?);
     file.write_all(&output.stdout)?;
+    file.flush()?;
 
-    // Encrypt and upload the package list
+    // Upload the package list; usually most of its chunks are already stored
     let file_data = fs::read(packages_file_path)?;
-    let encrypted_data = encryption::encrypt_data(&file_data)?;
-    supabase::upload_file(packages_file_path, &encrypted_data).await?;
+    let metadata = fs::metadata(packages_file_path)?;
+    let sha256 = snapshot::content_hash(&file_data);
+
+    // Reuse the previous upload outright when the package selection hasn't changed
+    let unchanged = previous
+        .and_then(|manifest| manifest.packages.as_ref())
+        .filter(|entry| entry.sha256 == sha256);
+    if let Some(entry) = unchanged {
+        write_log("Installed packages unchanged, reusing previous upload.");
+        return Ok(FileEntry::with_hash(packages_file_path, &metadata, sha256, entry.chunks.clone()));
+    }
 
-    Ok(())
+    let chunks = uploader.upload(&file_data).await?;
+    Ok(FileEntry::with_hash(packages_file_path, &metadata, sha256, chunks))
 }
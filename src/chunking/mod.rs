@@ -0,0 +1,322 @@
+use crate::compression::{self, CompressionSettings};
+use crate::encryption::{self, DataKey, WrappedKey};
+use crate::snapshot::{self, content_hash, Manifest};
+use crate::storage::{self, StorageError};
+use fastcdc::v2020::FastCDC;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+
+// Chunk size bounds for FastCDC. Config files usually fit in one chunk,
+// while an append to a long shell history only produces a new trailing chunk.
+const MIN_CHUNK_SIZE: u32 = 4 * 1024;
+const AVG_CHUNK_SIZE: u32 = 16 * 1024;
+const MAX_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Content-defined boundaries of `data`, as slices paired with their hex SHA-256
+pub fn split(data: &[u8]) -> Vec<(String, &[u8])> {
+    FastCDC::new(data, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE)
+        .map(|chunk| {
+            let bytes = &data[chunk.offset..chunk.offset + chunk.length];
+            (content_hash(bytes), bytes)
+        })
+        .collect()
+}
+
+/// Names chunk objects in storage. By default a chunk is addressed by the hash of its plaintext,
+/// so identical data is stored once. Opaque names are an HMAC of that hash under a key kept in the
+/// encrypted manifest: deduplication still works, but the bucket no longer reveals which contents it holds.
+pub struct ChunkNamer {
+    name_key: Option<Vec<u8>>,
+}
+
+impl ChunkNamer {
+    /// The naming used by a snapshot
+    pub fn for_manifest(manifest: &Manifest) -> Result<Self, Box<dyn Error>> {
+        let name_key = manifest.name_key.as_ref().map(encryption::unwrap_key).transpose()?;
+        Ok(ChunkNamer { name_key })
+    }
+
+    /// Object key of the chunk with this plaintext hash
+    pub fn chunk_key(&self, hash: &str) -> Result<String, Box<dyn Error>> {
+        match &self.name_key {
+            None => Ok(format!("chunks/{}", hash)),
+            Some(name_key) => {
+                let name_key = PKey::hmac(name_key)?;
+                let mut signer = Signer::new(MessageDigest::sha256(), &name_key)?;
+                signer.update(hash.as_bytes())?;
+                let name: String = signer.sign_to_vec()?.iter().map(|byte| format!("{:02x}", byte)).collect();
+                Ok(format!("objects/{}", name))
+            }
+        }
+    }
+}
+
+/// Key ID of every chunk a manifest refers to, and the data keys among them
+pub type ChunkKeys = (BTreeMap<String, String>, Vec<WrappedKey>);
+
+/// Uploads chunks for one backup run, skipping those already in storage, and tracks the data key
+/// each chunk is encrypted with so the manifest lists exactly the keys it needs
+pub struct ChunkUploader {
+    known: HashMap<String, String>, // Chunks known to exist remotely, with the ID of their key
+    keys: Vec<WrappedKey>, // Data keys chunks may be encrypted with, starting with those of the previous snapshot
+    compression: CompressionSettings,
+    data_key: DataKey, // Key of the snapshot being written
+    data_key_recorded: bool,
+    namer: ChunkNamer,
+    uploaded_bytes: u64,
+}
+
+impl ChunkUploader {
+    /// An uploader building on `previous`, whose chunks are reused when `reusable` is set
+    pub fn new(
+        previous: Option<&Manifest>,
+        reusable: bool,
+        compression: CompressionSettings,
+        data_key: DataKey,
+        namer: ChunkNamer,
+    ) -> Self {
+        let known = previous
+            .filter(|_| reusable)
+            .map(|manifest| {
+                manifest
+                    .chunk_hashes()
+                    .filter_map(|hash| Some((hash.clone(), manifest.chunk_keys.get(hash)?.clone())))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let keys = previous.map(|manifest| manifest.data_keys.clone()).unwrap_or_default();
+        ChunkUploader {
+            known,
+            keys,
+            compression,
+            data_key,
+            data_key_recorded: false,
+            namer,
+            uploaded_bytes: 0,
+        }
+    }
+
+    /// Ciphertext uploaded so far
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.uploaded_bytes
+    }
+
+    /// Split `data` into chunks and upload the new ones, returning the chunk hashes in order
+    pub async fn upload(&mut self, data: &[u8]) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut hashes = Vec::new();
+        for (hash, bytes) in split(data) {
+            if !self.known.contains_key(&hash) {
+                let key_id = self.store(&hash, bytes).await?;
+                self.known.insert(hash.clone(), key_id);
+            }
+            hashes.push(hash);
+        }
+        Ok(hashes)
+    }
+
+    /// The keys `manifest` needs, given the chunks uploaded or reused by this run
+    pub fn chunk_keys(&self, manifest: &Manifest) -> Result<ChunkKeys, Box<dyn Error>> {
+        let mut chunk_keys = BTreeMap::new();
+        for hash in manifest.chunk_hashes() {
+            let key_id = self.known.get(hash).ok_or_else(|| format!("Chunk {} was never uploaded.", hash))?;
+            chunk_keys.insert(hash.clone(), key_id.clone());
+        }
+
+        let used: HashSet<&String> = chunk_keys.values().collect();
+        let mut data_keys: Vec<WrappedKey> = Vec::new();
+        for wrapped in self.keys.iter().chain([self.data_key.wrapped()]) {
+            if used.contains(&wrapped.id) && !data_keys.iter().any(|key| key.id == wrapped.id) {
+                data_keys.push(wrapped.clone());
+            }
+        }
+        Ok((chunk_keys, data_keys))
+    }
+
+    // Encrypt and upload one chunk, returning the ID of the key it ends up stored under
+    async fn store(&mut self, hash: &str, bytes: &[u8]) -> Result<String, Box<dyn Error>> {
+        // The key is stored before anything is encrypted with it, so a run that fails halfway
+        // never leaves chunks behind whose key can't be found
+        if !self.data_key_recorded {
+            snapshot::record_data_key(self.data_key.wrapped()).await?;
+            self.data_key_recorded = true;
+        }
+
+        let key = self.namer.chunk_key(hash)?;
+        let compressed_data = compression::compress(bytes, &self.compression)?;
+        let encrypted_data = self.data_key.encrypt(&compressed_data)?;
+        let result = storage::backend()?.put(&key, &encrypted_data, false).await;
+        match result {
+            Ok(()) => {}
+            // Stored before by a snapshot this one doesn't build on, or by a run that failed. The chunk
+            // is shared if its key can be found; otherwise nothing can read it and it is replaced.
+            Err(StorageError::Conflict(_)) => {
+                if let Some(key_id) = self.readable_key(&key, hash).await? {
+                    return Ok(key_id);
+                }
+                storage::backend()?.put(&key, &encrypted_data, true).await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        self.uploaded_bytes += encrypted_data.len() as u64;
+        Ok(self.data_key.wrapped().id.clone())
+    }
+
+    // The key ID of a chunk already in storage, if it decrypts to the expected contents
+    async fn readable_key(&mut self, object_key: &str, hash: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let existing = storage::backend()?.get(object_key).await?;
+        let key_id = encryption::object_key_id(&existing).unwrap_or_default();
+        if let Some(wrapped) = self.find_data_key(&key_id).await? {
+            // A key wrapped under a retired master key can't be used, so the chunk is replaced
+            if encryption::load_data_keys(&[wrapped]).is_err() {
+                return Ok(None);
+            }
+        }
+        let readable = encryption::decrypt_data(&existing)
+            .ok()
+            .and_then(|compressed_data| compression::decompress(&compressed_data).ok())
+            .is_some_and(|bytes| content_hash(&bytes) == hash);
+        Ok(readable.then_some(key_id))
+    }
+
+    // The data key with this ID, from the previous snapshot or stored on its own
+    async fn find_data_key(&mut self, key_id: &str) -> Result<Option<WrappedKey>, Box<dyn Error>> {
+        if key_id.is_empty() {
+            return Ok(None);
+        }
+        if let Some(wrapped) = self.keys.iter().chain([self.data_key.wrapped()]).find(|key| key.id == key_id) {
+            return Ok(Some(wrapped.clone()));
+        }
+        let found = snapshot::fetch_data_key(key_id).await?;
+        self.keys.extend(found.clone());
+        Ok(found)
+    }
+}
+
+/// Download, decrypt and reassemble a sequence of chunks, checking each against its hash
+pub async fn download_chunks(hashes: &[String], namer: &ChunkNamer) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data = Vec::new();
+    for hash in hashes {
+        let encrypted_data = storage::backend()?.get(&namer.chunk_key(hash)?).await?;
+        let bytes = compression::decompress(&encryption::decrypt_data(&encrypted_data)?)?;
+        if content_hash(&bytes) != *hash {
+            return Err(format!("Chunk {} is corrupted.", hash).into());
+        }
+        data.extend_from_slice(&bytes);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Codec;
+    use openssl::rand::rand_bytes;
+    use serde_json::json;
+
+    fn setup() -> (CompressionSettings, ChunkNamer) {
+        storage::use_test_storage();
+        encryption::unlock_for_tests();
+        (CompressionSettings::new(Codec::Zstd, None), ChunkNamer { name_key: None })
+    }
+
+    // Random contents small enough to make up a single chunk, so no two tests share chunks
+    fn random_data() -> Vec<u8> {
+        let mut data = vec![0u8; 2048];
+        rand_bytes(&mut data).unwrap();
+        data
+    }
+
+    fn manifest_with(chunks: &[String], chunk_keys: &BTreeMap<String, String>, data_keys: &[WrappedKey]) -> Manifest {
+        serde_json::from_value(json!({
+            "snapshot_id": "20240101T000000Z",
+            "host": "test",
+            "os_name": "Ubuntu",
+            "os_version": "22.04",
+            "created_at": "2024-01-01T00:00:00Z",
+            "files": [{
+                "path": "/etc/test",
+                "size": 0,
+                "mode": 0o644,
+                "owner": "root",
+                "mtime": 0,
+                "sha256": "",
+                "chunks": chunks,
+            }],
+            "data_keys": data_keys,
+            "chunk_keys": chunk_keys,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn chunk_left_by_a_failed_run_is_replaced() {
+        let (compression, namer) = setup();
+        let data = random_data();
+
+        // Encrypted under a key that was never recorded anywhere
+        let mut lost_key = vec![0u8; 32];
+        rand_bytes(&mut lost_key).unwrap();
+        let orphan = encryption::encrypt_with_key(&lost_key, &compression::compress(&data, &compression).unwrap()).unwrap();
+        let object_key = namer.chunk_key(&content_hash(&data)).unwrap();
+        storage::backend().unwrap().put(&object_key, &orphan, false).await.unwrap();
+
+        let mut uploader = ChunkUploader::new(None, false, compression, DataKey::generate().unwrap(), namer);
+        let hashes = uploader.upload(&data).await.unwrap();
+        assert!(uploader.uploaded_bytes() > 0);
+
+        let (chunk_keys, data_keys) = uploader.chunk_keys(&manifest_with(&hashes, &BTreeMap::new(), &[])).unwrap();
+        let key_id = &uploader.data_key.wrapped().id;
+        assert_eq!(chunk_keys[&hashes[0]], *key_id);
+        assert_eq!(data_keys.iter().map(|key| &key.id).collect::<Vec<_>>(), vec![key_id]);
+        assert_eq!(download_chunks(&hashes, &uploader.namer).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn chunk_under_a_recorded_key_is_shared() {
+        let (compression, namer) = setup();
+        let data = random_data();
+
+        let mut first = ChunkUploader::new(None, false, compression, DataKey::generate().unwrap(), namer);
+        first.upload(&data).await.unwrap();
+
+        // A run that doesn't build on the first one finds the chunk and keeps it with the first key
+        let namer = ChunkNamer { name_key: None };
+        let mut second = ChunkUploader::new(None, false, compression, DataKey::generate().unwrap(), namer);
+        let hashes = second.upload(&data).await.unwrap();
+        assert_eq!(second.uploaded_bytes(), 0);
+
+        let (chunk_keys, data_keys) = second.chunk_keys(&manifest_with(&hashes, &BTreeMap::new(), &[])).unwrap();
+        let first_id = &first.data_key.wrapped().id;
+        assert_eq!(chunk_keys[&hashes[0]], *first_id);
+        assert_eq!(data_keys.iter().map(|key| &key.id).collect::<Vec<_>>(), vec![first_id]);
+    }
+
+    #[tokio::test]
+    async fn only_keys_of_referenced_chunks_are_carried() {
+        let (compression, _) = setup();
+        let (kept, dropped) = (random_data(), random_data());
+
+        let mut keys = Vec::new();
+        let mut hashes = Vec::new();
+        let mut chunk_keys = BTreeMap::new();
+        for data in [&kept, &dropped] {
+            let mut uploader = ChunkUploader::new(None, false, compression, DataKey::generate().unwrap(), ChunkNamer { name_key: None });
+            let hash = uploader.upload(data).await.unwrap().remove(0);
+            chunk_keys.insert(hash.clone(), uploader.data_key.wrapped().id.clone());
+            keys.push(uploader.data_key.wrapped().clone());
+            hashes.push(hash);
+        }
+
+        let previous = manifest_with(&hashes, &chunk_keys, &keys);
+        let mut uploader = ChunkUploader::new(Some(&previous), true, compression, DataKey::generate().unwrap(), ChunkNamer { name_key: None });
+        let reused = uploader.upload(&kept).await.unwrap();
+        assert_eq!(uploader.uploaded_bytes(), 0);
+
+        let (chunk_keys, data_keys) = uploader.chunk_keys(&manifest_with(&reused, &BTreeMap::new(), &[])).unwrap();
+        assert_eq!(chunk_keys[&hashes[0]], keys[0].id);
+        assert_eq!(data_keys.iter().map(|key| &key.id).collect::<Vec<_>>(), vec![&keys[0].id]);
+    }
+}
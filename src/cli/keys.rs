@@ -0,0 +1,86 @@
+use crate::compression::CompressionSettings;
+use crate::config::get_compression_settings;
+use crate::encryption;
+use crate::logging::write_log;
+use crate::snapshot;
+use colored::Colorize;
+use inquire::{Confirm, Text};
+use std::error::Error;
+
+/// Move every snapshot to a new master key by rewrapping its data keys. Chunks are not touched.
+pub async fn rotate_keys() -> Result<(), Box<dyn Error>> {
+    let backups = snapshot::fetch_snapshots().await?;
+    let compression = get_compression_settings()?;
+    let version = encryption::rotate_master_key().await?;
+    write_log(&format!("Rotating to master key version {}", version));
+
+    let mut failed = 0;
+    for backup in &backups {
+        match rewrap_snapshot(&backup.snapshot_id, &compression).await {
+            Ok(()) => write_log(&format!("Rewrapped keys of snapshot {}", backup.snapshot_id)),
+            Err(e) => {
+                write_log(&format!("Failed to rewrap snapshot {}: {}", backup.snapshot_id, e));
+                failed += 1;
+            }
+        }
+    }
+    // Older master keys are only dropped once no snapshot depends on them, so a failed run can be repeated
+    if failed > 0 {
+        return Err(format!(
+            "{} of {} snapshots could not be rewrapped. The previous master key was kept; run the rotation again.",
+            failed,
+            backups.len()
+        )
+        .into());
+    }
+    // The data keys stored apart from the manifests depend on it too
+    let stored_keys = snapshot::rewrap_data_keys().await.map_err(|e| {
+        format!("Failed to rewrap the stored data keys: {}. The previous master key was kept; run the rotation again.", e)
+    })?;
+    encryption::retire_old_master_keys().await?;
+    println!(
+        "{}",
+        format!(
+            "Rewrapped {} snapshots and {} stored data keys under master key version {}.",
+            backups.len(),
+            stored_keys,
+            version
+        )
+        .green()
+    );
+    Ok(())
+}
+
+async fn rewrap_snapshot(snapshot_id: &str, compression: &CompressionSettings) -> Result<(), Box<dyn Error>> {
+    let mut manifest = snapshot::fetch_manifest(snapshot_id).await?;
+    manifest.data_keys = encryption::rewrap_keys(&manifest.data_keys)?;
+    manifest.name_key = encryption::rewrap_keys(manifest.name_key.as_slice())?.pop();
+    snapshot::replace_manifest(&manifest, compression).await
+}
+
+/// Print the recovery code for the current master key
+pub fn export_key() -> Result<(), Box<dyn Error>> {
+    let code = encryption::export_recovery_code()?;
+    println!("{}", "Recovery code".bold());
+    println!("Anyone with this code can decrypt your backups. Write it down and keep it somewhere safe.");
+    println!();
+    println!("{}", code);
+    println!();
+    println!("On a new machine, or if you forget your passphrase, run `key recover` and enter this code.");
+    Ok(())
+}
+
+/// Import a recovery code and protect the recovered key with a new passphrase
+pub async fn recover_key() -> Result<(), Box<dyn Error>> {
+    let code = Text::new("Enter your recovery code:").prompt()?;
+    let confirmed = Confirm::new("This replaces your encryption passphrase for every machine. Continue?")
+        .with_default(false)
+        .prompt()?;
+    if !confirmed {
+        return Ok(());
+    }
+
+    encryption::recover(&code).await?;
+    println!("{}", "Key recovered. Your backups can be restored with the new passphrase.".green());
+    Ok(())
+}
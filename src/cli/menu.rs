@@ -1,9 +1,14 @@
-use inquire::Select;
+use inquire::{Confirm, MultiSelect, Select};
+use chrono::Local;
+use glob::Pattern;
 use colored::*; // Add colored for terminal colors and text styling
 use crate::auth;
 use crate::backup;
+use crate::encryption;
 use crate::restore;
+use crate::snapshot;
 use crate::cli::print_to_dashboard::print_to_dashboard_with_coordinates;
+use crate::cli::snapshots;
 use std::error::Error;
 use std::io::{stdout, Write};
 
@@ -33,7 +38,12 @@ pub async fn show_dashboard() -> Result<(), Box<dyn Error>> {
         let mut actions = vec![];
         if auth::is_logged_in() {
             actions = vec![
-                "Backup", "Restore", "Logout", "Check Status", "Quit"
+                "Backup", "Restore", "Undo Last Restore", "Logout", "Check Status", "Quit"
+            ];
+        } else if auth::can_back_up() {
+            // Storage outside Supabase needs no account
+            actions = vec![
+                "Backup", "Restore", "Undo Last Restore", "Check Status", "Quit"
             ];
         } else {
             actions = vec![
@@ -44,7 +54,12 @@ pub async fn show_dashboard() -> Result<(), Box<dyn Error>> {
  
         // Extra dashboard info printed to the right side (without interfering with the main menu)
         print_to_dashboard_with_coordinates("Server Status: Running".green().to_string().as_str(), 50, 5);
-        print_to_dashboard_with_coordinates("Last Backup: 2 hours ago".yellow().to_string().as_str(), 50, 6);
+        let last_backup = if auth::can_back_up() {
+            snapshots::last_backup_summary().await
+        } else {
+            "Last Backup: unknown".to_string()
+        };
+        print_to_dashboard_with_coordinates(last_backup.yellow().to_string().as_str(), 50, 6);
         print_to_dashboard_with_coordinates("OS: Ubuntu 20.04\n".blue().to_string().as_str(), 50, 7);
 
         // Prompt the user for action
@@ -70,22 +85,45 @@ pub async fn show_dashboard() -> Result<(), Box<dyn Error>> {
             }
             "Backup" => {
                 clear_screen();
-                if auth::is_logged_in() {
-                    backup::backup_system().await?;
-                    print_to_dashboard_with_coordinates("Backup completed successfully.".green().to_string().as_str(), 0, 12);
+                if auth::can_back_up() {
+                    // Backups stay locked when the passphrase was skipped at startup, so ask again here
+                    let result = match encryption::unlock().await {
+                        Ok(()) => backup::backup_system().await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(()) => print_to_dashboard_with_coordinates("Backup completed successfully.".green().to_string().as_str(), 0, 12),
+                        Err(e) => print_to_dashboard_with_coordinates(e.to_string().red().to_string().as_str(), 0, 12),
+                    }
                 } else {
                     print_to_dashboard_with_coordinates("Please log in first.".red().to_string().as_str(), 0, 12);
                 }
             }
             "Restore" => {
                 clear_screen();
-                if auth::is_logged_in() {
-                    restore::restore_files().await?;
-                    print_to_dashboard_with_coordinates("Restore completed successfully.".green().to_string().as_str(), 0, 12);
+                if auth::can_back_up() {
+                    let result = match encryption::unlock().await {
+                        Ok(()) => match pick_restore_options().await {
+                            Ok(options) => restore::restore_files(&options).await,
+                            Err(e) => Err(e),
+                        },
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(()) => print_to_dashboard_with_coordinates("Restore completed successfully.".green().to_string().as_str(), 0, 12),
+                        Err(e) => print_to_dashboard_with_coordinates(e.to_string().red().to_string().as_str(), 0, 12),
+                    }
                 } else {
                     print_to_dashboard_with_coordinates("Please log in first.".red().to_string().as_str(), 0, 12);
                 }
             }
+            "Undo Last Restore" => {
+                clear_screen();
+                match restore::rollback::undo_last_restore() {
+                    Ok(()) => print_to_dashboard_with_coordinates("Undo completed successfully.".green().to_string().as_str(), 0, 12),
+                    Err(e) => print_to_dashboard_with_coordinates(e.to_string().red().to_string().as_str(), 0, 12),
+                }
+            }
             "Check Status" => {
                 clear_screen();
                 auth::session_status()?;
@@ -124,6 +162,55 @@ async fn signup() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+// Let the user pick a backup and the files to restore from it
+async fn pick_restore_options() -> Result<restore::RestoreOptions, Box<dyn Error>> {
+    let backups = snapshot::fetch_snapshots().await?;
+    if backups.is_empty() {
+        return Err("No backups found.".into());
+    }
+
+    let labels: Vec<String> = backups
+        .iter()
+        .map(|backup| {
+            let date = backup.backup_date.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+            match (&backup.host, backup.file_count) {
+                (Some(host), Some(file_count)) => format!("{}  {}  ({} files)", date, host, file_count),
+                _ => date.to_string(),
+            }
+        })
+        .collect();
+    let choice = Select::new("Choose a backup to restore:", labels).raw_prompt()?;
+    let snapshot_id = backups[choice.index].snapshot_id.clone();
+
+    let manifest = snapshot::fetch_manifest(&snapshot_id).await?;
+    let files: Vec<&str> = manifest.files.iter().map(|entry| entry.path.as_str()).collect();
+    let selected = MultiSelect::new("Choose the files to restore:", files.clone())
+        .with_all_selected_by_default()
+        .prompt()?;
+    if selected.is_empty() {
+        return Err("No files selected.".into());
+    }
+
+    // Restoring everything needs no filter; otherwise match the chosen paths literally
+    let paths = if selected.len() == files.len() {
+        Vec::new()
+    } else {
+        selected.iter().map(|path| Pattern::escape(path)).collect()
+    };
+
+    let packages = manifest.packages.is_some()
+        && Confirm::new("Also reinstall missing packages from this backup?")
+            .with_default(false)
+            .prompt()?;
+
+    Ok(restore::RestoreOptions {
+        snapshot_id: Some(snapshot_id),
+        paths,
+        packages,
+        ..Default::default()
+    })
+}
+
 // Reset password function
 async fn reset_password() -> Result<(), Box<dyn Error>> {
     let email = inquire::Text::new("Enter your email:").prompt()?;
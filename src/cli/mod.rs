@@ -1,12 +1,18 @@
 use clap::{Parser, Subcommand};
 use crate::auth;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, Utc};
 use nix::unistd::Uid;
+use std::path::PathBuf;
 
+pub mod keys;
 pub mod menu;
 pub mod print_to_dashboard;
+pub mod snapshots;
 
 use crate::backup;
+use crate::encryption;
 use crate::restore;
+use crate::storage;
 
 /// Define the CLI structure with clap
 #[derive(Parser)]
@@ -14,13 +20,64 @@ use crate::restore;
 #[command(about = "A tool to backup and restore user configurations", long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
-    pub command: Commands,
+    pub command: Option<Commands>,
 }
 
 fn is_root() -> bool {
     Uid::effective().is_root()
 }
 
+/// Parse an RFC 3339 timestamp, or a local date with optional time
+fn parse_timestamp(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| format!("Invalid timestamp: {}", value))?;
+    naive
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| format!("Invalid local time: {}", value))
+}
+
+/// Check for a session and refresh its access token, printing the reason when there is none.
+/// Storage outside Supabase needs no session.
+async fn logged_in() -> bool {
+    if !storage::needs_login() {
+        return true;
+    }
+    if !auth::is_logged_in() {
+        println!("Please log in first.");
+        return false;
+    }
+    match auth::access_token().await {
+        Ok(_) => true,
+        Err(e) => {
+            println!("{}", e);
+            false
+        }
+    }
+}
+
+/// Ask for the passphrase, printing the reason when the key can't be unlocked
+async fn unlock() -> bool {
+    match encryption::unlock().await {
+        Ok(()) => true,
+        Err(e) => {
+            println!("{}", e);
+            false
+        }
+    }
+}
+
 /// Define the available subcommands
 #[derive(Subcommand)]
 pub enum Commands {
@@ -44,12 +101,61 @@ pub enum Commands {
     /// Backup all configuration files and package lists
     Backup {},
     /// Restore configuration files and packages
-    Restore {},
+    Restore {
+        /// Snapshot to restore (see `snapshots`); defaults to the latest
+        #[arg(long, conflicts_with = "before")]
+        snapshot: Option<String>,
+        /// Restore the newest snapshot taken before this time, e.g. "2024-05-01" or "2024-05-01 18:00"
+        #[arg(long, value_parser = parse_timestamp)]
+        before: Option<DateTime<Utc>>,
+        /// Only restore files matching these globs, e.g. "~/.gitconfig" or "/etc/apt/**"
+        paths: Vec<String>,
+        /// Compare the backup with the files on disk without changing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// With --dry-run, show a unified diff for changed text files
+        #[arg(long, requires = "dry_run")]
+        diff: bool,
+        /// Also reinstall packages from the backed-up package list that are missing on this host
+        #[arg(long, conflicts_with = "target_root")]
+        packages: bool,
+        /// With --packages, let apt simulate the installation instead of running it
+        #[arg(long, requires = "packages")]
+        simulate: bool,
+        /// Restore under this directory instead of `/`, e.g. /etc/environment to <DIR>/etc/environment
+        #[arg(long, value_name = "DIR")]
+        target_root: Option<PathBuf>,
+        /// Put back the files overwritten by the last restore, using the copies saved locally before it ran
+        #[arg(long, exclusive = true)]
+        undo: bool,
+    },
+    /// List the backups stored for the current user
+    Snapshots {},
+    /// Show the files contained in one backup
+    Show {
+        id: String,
+    },
+    /// Manage the keys backups are encrypted with
+    Key {
+        #[command(subcommand)]
+        action: KeyCommands,
+    },
+}
+
+/// Subcommands of `key`
+#[derive(Subcommand)]
+pub enum KeyCommands {
+    /// Create a new master key and rewrap every snapshot's data keys under it, without re-uploading any data
+    Rotate {},
+    /// Print the master key as a recovery code to keep on paper
+    Export {},
+    /// Import a recovery code on a new machine, or after forgetting the passphrase, and choose a new passphrase
+    Recover {},
 }
 
 /// Handle the parsed CLI command
-pub async fn handle_command(cli: &Cli) {
-    match &cli.command {
+pub async fn handle_command(command: &Commands) {
+    match command {
         Commands::Signup { email, password } => {
             auth::sign_up(email, password).await.unwrap();
         }
@@ -70,22 +176,114 @@ pub async fn handle_command(cli: &Cli) {
                 println!("Please run this command as root or with sudo.");
                 return;
             }
-            if auth::is_logged_in() {
+            if logged_in().await && unlock().await {
                 backup::backup_system().await.unwrap();
-            } else {
-                println!("Please log in first.");
             }
         }
-        Commands::Restore {} => {
-            if !is_root() {
+        Commands::Restore { snapshot, before, paths, dry_run, diff, packages, simulate, target_root, undo } => {
+            // Undo works from local copies only, so it doesn't need a session or the network
+            if *undo {
+                if let Err(e) = restore::rollback::undo_last_restore() {
+                    println!("{}", e);
+                }
+                return;
+            }
+            // Restoring into a scratch directory or only previewing doesn't need to touch system files
+            if !is_root() && !dry_run && target_root.is_none() {
                 println!("Please run this command as root or with sudo.");
                 return;
             }
-            if auth::is_logged_in() {
-                restore::restore_files().await.unwrap();
-            } else {
-                println!("Please log in first.");
+            if !logged_in().await || !unlock().await {
+                return;
+            }
+            let options = restore::RestoreOptions {
+                snapshot_id: snapshot.clone(),
+                before: *before,
+                paths: paths.clone(),
+                dry_run: *dry_run,
+                show_diff: *diff,
+                packages: *packages,
+                simulate: *simulate,
+                target_root: target_root.clone(),
+            };
+            if let Err(e) = restore::restore_files(&options).await {
+                println!("{}", e);
+            }
+        }
+        Commands::Snapshots {} => {
+            if logged_in().await {
+                if let Err(e) = snapshots::list_snapshots().await {
+                    println!("{}", e);
+                }
+            }
+        }
+        Commands::Show { id } => {
+            if logged_in().await && unlock().await {
+                if let Err(e) = snapshots::show_snapshot(id).await {
+                    println!("{}", e);
+                }
             }
         }
+        Commands::Key { action } => {
+            let result = match action {
+                KeyCommands::Rotate {} => {
+                    if !logged_in().await || !unlock().await {
+                        return;
+                    }
+                    keys::rotate_keys().await
+                }
+                KeyCommands::Export {} => {
+                    if !unlock().await {
+                        return;
+                    }
+                    keys::export_key()
+                }
+                // Recovering is how a forgotten passphrase is replaced, so it can't ask for it
+                KeyCommands::Recover {} => {
+                    if !logged_in().await {
+                        return;
+                    }
+                    keys::recover_key().await
+                }
+            };
+            if let Err(e) = result {
+                println!("{}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(value: &str) -> DateTime<Utc> {
+        NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+            .unwrap()
+            .and_local_timezone(Local)
+            .earliest()
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn rfc_3339_timestamps_keep_their_offset() {
+        let expected = "2024-03-01T08:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(parse_timestamp("2024-03-01T10:30:00+02:00"), Ok(expected));
+        assert_eq!(parse_timestamp("2024-03-01T08:30:00Z"), Ok(expected));
+    }
+
+    #[test]
+    fn dates_and_times_are_local() {
+        assert_eq!(parse_timestamp("2024-03-01 10:30:15"), Ok(local("2024-03-01 10:30:15")));
+        assert_eq!(parse_timestamp("2024-03-01 10:30"), Ok(local("2024-03-01 10:30:00")));
+        assert_eq!(parse_timestamp("2024-03-01"), Ok(local("2024-03-01 00:00:00")));
+    }
+
+    #[test]
+    fn invalid_timestamps_are_rejected() {
+        for value in ["", "yesterday", "2024-13-01", "2024-03-01 25:00", "01/03/2024"] {
+            assert_eq!(parse_timestamp(value), Err(format!("Invalid timestamp: {}", value)));
+        }
     }
 }
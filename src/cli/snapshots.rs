@@ -0,0 +1,113 @@
+use crate::snapshot;
+use chrono::{DateTime, Local, Utc};
+use colored::Colorize;
+use std::error::Error;
+
+/// Print every snapshot of the current user, newest first
+pub async fn list_snapshots() -> Result<(), Box<dyn Error>> {
+    let backups = snapshot::fetch_snapshots().await?;
+    if backups.is_empty() {
+        println!("No backups found.");
+        return Ok(());
+    }
+
+    println!(
+        "{}",
+        format!("{:<18} {:<20} {:<20} {:>6} {:>10} {:>10}", "ID", "DATE", "HOST", "FILES", "SIZE", "STORED").bold()
+    );
+    // Snapshots with opaque names only reveal their details once the manifest is decrypted (`show`)
+    for backup in backups {
+        println!(
+            "{:<18} {:<20} {:<20} {:>6} {:>10} {:>10}",
+            backup.snapshot_id,
+            backup.backup_date.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+            backup.host.as_deref().unwrap_or("-"),
+            backup.file_count.map(|count| count.to_string()).unwrap_or_else(|| "-".to_string()),
+            backup.file_size.map(format_size).unwrap_or_else(|| "-".to_string()),
+            backup.ciphertext_size.map(format_size).unwrap_or_else(|| "-".to_string())
+        );
+    }
+    Ok(())
+}
+
+/// Print the full file list of one snapshot
+pub async fn show_snapshot(snapshot_id: &str) -> Result<(), Box<dyn Error>> {
+    let manifest = snapshot::fetch_manifest(snapshot_id).await?;
+
+    println!("{} {}", "Snapshot:".bold(), manifest.snapshot_id);
+    println!("{} {}", "Date:".bold(), manifest.created_at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"));
+    println!("{} {}", "Host:".bold(), manifest.host);
+    println!("{} {} {}", "OS:".bold(), manifest.os_name, manifest.os_version);
+    println!(
+        "{} {} ({})",
+        "Files:".bold(),
+        manifest.files.len(),
+        format_size(manifest.total_size())
+    );
+    println!();
+
+    for entry in &manifest.files {
+        let mtime = DateTime::<Utc>::from_timestamp(entry.mtime, 0)
+            .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let path = match &entry.symlink_target {
+            Some(target) => format!("{} -> {}", entry.path, target),
+            None => entry.path.clone(),
+        };
+        println!(
+            "{:04o} {:<10} {:<10} {:>10} {} {}",
+            entry.mode,
+            entry.owner,
+            entry.group,
+            format_size(entry.size),
+            mtime,
+            path
+        );
+    }
+    if manifest.packages.is_some() {
+        println!("{}", "Installed package list included.".green());
+    }
+    Ok(())
+}
+
+/// Describe when the most recent backup ran, for the dashboard
+pub async fn last_backup_summary() -> String {
+    match snapshot::fetch_snapshots().await {
+        Ok(backups) => match backups.first() {
+            Some(backup) => format!("Last Backup: {}", format_age(backup.backup_date)),
+            None => "Last Backup: never".to_string(),
+        },
+        Err(_) => "Last Backup: unknown".to_string(),
+    }
+}
+
+// Human-readable byte count
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+// Relative time such as "2 hours ago"
+fn format_age(time: DateTime<Utc>) -> String {
+    let age = Utc::now().signed_duration_since(time);
+    let (amount, unit) = if age.num_days() > 0 {
+        (age.num_days(), "day")
+    } else if age.num_hours() > 0 {
+        (age.num_hours(), "hour")
+    } else if age.num_minutes() > 0 {
+        (age.num_minutes(), "minute")
+    } else {
+        return "just now".to_string();
+    };
+    format!("{} {}{} ago", amount, unit, if amount == 1 { "" } else { "s" })
+}
@@ -0,0 +1,149 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+// Every stored object starts with this marker followed by one codec byte
+const MAGIC: &[u8; 4] = b"CNZ1";
+
+/// Compression codec recorded in the header of every stored object
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    None = 0,
+    Zstd = 1,
+    Gzip = 2,
+}
+
+impl Codec {
+    fn from_byte(byte: u8) -> Result<Self, Box<dyn Error>> {
+        match byte {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            2 => Ok(Codec::Gzip),
+            _ => Err(format!("Unknown compression codec: {}", byte).into()),
+        }
+    }
+
+    fn default_level(self) -> i32 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 3,
+            Codec::Gzip => 6,
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "none" => Ok(Codec::None),
+            "zstd" => Ok(Codec::Zstd),
+            "gzip" => Ok(Codec::Gzip),
+            _ => Err(format!("Unknown compression codec: {}", value)),
+        }
+    }
+}
+
+/// Codec and level applied to data before it is encrypted
+#[derive(Clone, Copy, Debug)]
+pub struct CompressionSettings {
+    pub codec: Codec,
+    pub level: i32,
+}
+
+impl CompressionSettings {
+    /// Settings for a codec, using its default level unless one is given
+    pub fn new(codec: Codec, level: Option<i32>) -> Self {
+        let level = match codec {
+            Codec::None => 0,
+            Codec::Zstd => level.unwrap_or(codec.default_level()).clamp(1, 22),
+            Codec::Gzip => level.unwrap_or(codec.default_level()).clamp(0, 9),
+        };
+        CompressionSettings { codec, level }
+    }
+}
+
+/// Compress data and prefix it with a header naming the codec.
+/// Data that doesn't shrink, such as already-compressed files, is stored uncompressed.
+pub fn compress(data: &[u8], settings: &CompressionSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    let compressed = match settings.codec {
+        Codec::None => None,
+        Codec::Zstd => Some(zstd::encode_all(data, settings.level)?),
+        Codec::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(settings.level as u32));
+            encoder.write_all(data)?;
+            Some(encoder.finish()?)
+        }
+    };
+
+    let (codec, payload) = match compressed {
+        Some(compressed) if compressed.len() < data.len() => (settings.codec, compressed),
+        _ => (Codec::None, data.to_vec()),
+    };
+
+    let mut output = Vec::with_capacity(MAGIC.len() + 1 + payload.len());
+    output.extend_from_slice(MAGIC);
+    output.push(codec as u8);
+    output.extend_from_slice(&payload);
+    Ok(output)
+}
+
+/// Undo `compress`, using the codec named in the header
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let rest = data.strip_prefix(MAGIC).ok_or("Missing compression header.")?;
+    let (&codec, payload) = rest.split_first().ok_or("Truncated compression header.")?;
+
+    match Codec::from_byte(codec)? {
+        Codec::None => Ok(payload.to_vec()),
+        Codec::Zstd => Ok(zstd::decode_all(payload)?),
+        Codec::Gzip => {
+            let mut output = Vec::new();
+            GzDecoder::new(payload).read_to_end(&mut output)?;
+            Ok(output)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"export PATH=$HOME/bin:$PATH\nexport PATH=$HOME/bin:$PATH\nexport PATH=$HOME/bin:$PATH\n";
+
+    #[test]
+    fn every_codec_round_trips() {
+        for codec in [Codec::None, Codec::Zstd, Codec::Gzip] {
+            let compressed = compress(TEXT, &CompressionSettings::new(codec, None)).unwrap();
+            assert_eq!(compressed[MAGIC.len()], codec as u8);
+            assert_eq!(decompress(&compressed).unwrap(), TEXT);
+        }
+    }
+
+    #[test]
+    fn data_that_does_not_shrink_is_stored_as_is() {
+        let compressed = compress(b"x", &CompressionSettings::new(Codec::Zstd, None)).unwrap();
+        assert_eq!(compressed, [&MAGIC[..], &[Codec::None as u8], b"x"].concat());
+        assert_eq!(decompress(&compressed).unwrap(), b"x");
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        assert!(decompress(TEXT).is_err());
+        assert!(decompress(MAGIC).is_err());
+        assert!(decompress(&[&MAGIC[..], &[9], TEXT].concat()).is_err());
+        assert!(decompress(&[&MAGIC[..], &[Codec::Zstd as u8], TEXT].concat()).is_err());
+    }
+
+    #[test]
+    fn levels_are_clamped_per_codec() {
+        assert_eq!(CompressionSettings::new(Codec::Zstd, Some(99)).level, 22);
+        assert_eq!(CompressionSettings::new(Codec::Gzip, Some(99)).level, 9);
+        assert_eq!(CompressionSettings::new(Codec::None, Some(5)).level, 0);
+        assert_eq!("GZIP".parse::<Codec>(), Ok(Codec::Gzip));
+        assert!("lz4".parse::<Codec>().is_err());
+    }
+}
@@ -1,8 +1,10 @@
 pub mod ubuntu;
 
-use std::{error::Error, fs};
+use std::{error::Error, fs, path::PathBuf};
+use crate::compression::{Codec, CompressionSettings};
+use crate::storage::{S3Settings, SftpSettings, StorageSettings};
+use nix::unistd::{Uid, User};
 use crate::config::ubuntu::{get_ubuntu_config_files, is_ubuntu, load_init_settings};
-use sys_info::{os_type, os_release};
 
 pub fn get_os_details() -> Result<(String, String), Box<dyn Error>> {
     let os_release_content = fs::read_to_string("/etc/os-release")?;
@@ -29,24 +31,111 @@ pub fn get_os_details() -> Result<(String, String), Box<dyn Error>> {
 }
 // Function to load OS-specific configuration files, checks OS and loads appropriate config
 pub fn get_config_files() -> Result<Vec<String>, Box<dyn Error>> {
-    let mut config_files = Vec::new();
-
     // Check if OS is Ubuntu and load corresponding files
-    if is_ubuntu() {
-        config_files = get_ubuntu_config_files();
+    let mut config_files = if is_ubuntu() {
+        get_ubuntu_config_files()
     } else {
         return Err("Unsupported OS. Currently only Ubuntu is supported.".into());
-    }
+    };
 
     // Load exclusions from `.init` file
-    let (excluded_files, _) = load_init_settings()?;
+    let excluded_files = load_init_settings()?.excluded_files;
     config_files.retain(|file| !excluded_files.contains(file));
 
     Ok(config_files)
 }
 
+// Local state such as rollback copies and the backup index, e.g. /root/.local/share/continu
+pub fn data_dir() -> Result<PathBuf, Box<dyn Error>> {
+    let data_dir = dirs::data_local_dir().ok_or("Unable to determine local data directory")?;
+    Ok(data_dir.join("continu"))
+}
+
+// Per-user settings such as the key derivation parameters, e.g. /root/.config/continu
+pub fn config_dir() -> Result<PathBuf, Box<dyn Error>> {
+    let config_dir = dirs::config_dir().ok_or("Unable to determine config directory")?;
+    Ok(config_dir.join("continu"))
+}
+
 // For future use: dynamic config from the user
 pub fn get_backup_frequency() -> Result<String, Box<dyn Error>> {
-    let (_, frequency) = load_init_settings()?;
-    Ok(frequency)
+    Ok(load_init_settings()?.frequency)
+}
+
+// Compression applied before encryption, from `compression: zstd|gzip|none` and `compression_level: N`
+pub fn get_compression_settings() -> Result<CompressionSettings, Box<dyn Error>> {
+    let settings = load_init_settings()?;
+    let codec = settings
+        .get("compression")
+        .map(|codec| codec.parse::<Codec>())
+        .transpose()?
+        .unwrap_or(Codec::Zstd);
+    let level = settings
+        .get("compression_level")
+        .map(|level| level.parse::<i32>())
+        .transpose()?;
+    Ok(CompressionSettings::new(codec, level))
+}
+
+// Whether chunks are stored under opaque names and the backups table only gets the snapshot ID,
+// date and stored size, from `opaque_names: true`
+pub fn get_opaque_names() -> Result<bool, Box<dyn Error>> {
+    let opaque_names = load_init_settings()?
+        .get("opaque_names")
+        .map(|value| value.parse::<bool>())
+        .transpose()?;
+    Ok(opaque_names.unwrap_or(false))
+}
+
+// Where snapshots are stored, from `storage: supabase|local|s3|sftp`. The local backend writes under
+// `storage_path`, e.g. a USB disk or NAS mount. S3 reads `s3_bucket`, `s3_region`, `s3_endpoint`
+// (AWS by default) and `s3_path_style: true` for MinIO; credentials come from the AWS_* variables.
+// SFTP reads `sftp_host`, `sftp_path`, `sftp_port` (22), `sftp_user` (the current user) and
+// `sftp_key`; without a key file the SSH agent is used.
+pub fn get_storage_settings() -> Result<StorageSettings, Box<dyn Error>> {
+    let settings = load_init_settings()?;
+    match settings.get("storage").unwrap_or("supabase") {
+        "supabase" => Ok(StorageSettings::Supabase),
+        "local" => {
+            let path = settings.get("storage_path").ok_or("`storage: local` needs a `storage_path`.")?;
+            Ok(StorageSettings::Local { path: PathBuf::from(path) })
+        }
+        "s3" => {
+            let bucket = settings.get("s3_bucket").ok_or("`storage: s3` needs an `s3_bucket`.")?;
+            let region = settings.get("s3_region").unwrap_or("us-east-1");
+            let endpoint = settings
+                .get("s3_endpoint")
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+            let path_style = settings.get("s3_path_style").map(|value| value.parse::<bool>()).transpose()?;
+            Ok(StorageSettings::S3(S3Settings {
+                endpoint,
+                bucket: bucket.to_string(),
+                region: region.to_string(),
+                path_style: path_style.unwrap_or(false),
+            }))
+        }
+        "sftp" => {
+            let host = settings.get("sftp_host").ok_or("`storage: sftp` needs an `sftp_host`.")?;
+            let path = settings.get("sftp_path").ok_or("`storage: sftp` needs an `sftp_path`.")?;
+            let port = settings.get("sftp_port").map(|port| port.parse::<u16>()).transpose()?;
+            let user = match settings.get("sftp_user") {
+                Some(user) => user.to_string(),
+                None => User::from_uid(Uid::current())?.ok_or("Unable to determine the current user")?.name,
+            };
+            let home = dirs::home_dir().unwrap_or_default();
+            let key_file = settings.get("sftp_key").map(|key_file| match key_file.strip_prefix("~/") {
+                Some(rest) => home.join(rest),
+                None => PathBuf::from(key_file),
+            });
+            Ok(StorageSettings::Sftp(SftpSettings {
+                host: host.to_string(),
+                port: port.unwrap_or(22),
+                user,
+                path: PathBuf::from(path),
+                key_file,
+            }))
+        }
+        other => Err(format!("Unknown storage backend: {}", other).into()),
+    }
 }
@@ -1,6 +1,21 @@
+use std::collections::HashMap;
 use std::fs;
 use std::error::Error;
 
+/// Settings read from the `.init` file
+#[derive(Default)]
+pub struct InitSettings {
+    pub excluded_files: Vec<String>,
+    pub frequency: String,
+    pub values: HashMap<String, String>, // Every other `key: value` line
+}
+
+impl InitSettings {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+}
+
 // List of default Ubuntu configuration files to back up
 pub fn get_ubuntu_config_files() -> Vec<String> {
     vec![
@@ -21,22 +36,26 @@ pub fn is_ubuntu() -> bool {
 }
 
 // Function to load OS-specific settings from the `.init` file
-pub fn load_init_settings() -> Result<(Vec<String>, String), Box<dyn Error>> {
+pub fn load_init_settings() -> Result<InitSettings, Box<dyn Error>> {
     let init_file_path = dirs::home_dir().unwrap().join(".init");
-    let mut excluded_files = Vec::new();
-    let mut frequency = "daily".to_string(); // Default frequency
+    let mut settings = InitSettings {
+        frequency: "daily".to_string(), // Default frequency
+        ..Default::default()
+    };
 
     if init_file_path.exists() {
         let content = fs::read_to_string(init_file_path)?;
         for line in content.lines() {
             if line.starts_with("exclude:") {
                 let file = line.replace("exclude:", "").trim().to_string();
-                excluded_files.push(file);
+                settings.excluded_files.push(file);
             } else if line.starts_with("frequency:") {
-                frequency = line.replace("frequency:", "").trim().to_string();
+                settings.frequency = line.replace("frequency:", "").trim().to_string();
+            } else if let Some((key, value)) = line.split_once(':') {
+                settings.values.insert(key.trim().to_string(), value.trim().to_string());
             }
         }
     }
 
-    Ok((excluded_files, frequency))
+    Ok(settings)
 }
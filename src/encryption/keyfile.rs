@@ -0,0 +1,126 @@
+use super::{key_id, keyring, load_synced, save_synced, seal, Sealed, KEY_LEN};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use openssl::rand::rand_bytes;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+const KEY_FILE: &str = "keyfile.json";
+const REMOTE_KEY_FILE: &str = "keys/keyfile.json";
+
+/// Every master key version still in use, each wrapped by the passphrase key
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    current_version: u32, // Version that wraps new data keys and encrypts new manifests
+    master_keys: Vec<StoredKey>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    version: u32,
+    id: String,      // Base64 key ID, to check a recovery code against without the passphrase
+    wrapped: String, // Base64 master key, encrypted under the passphrase key
+}
+
+impl StoredKey {
+    fn generate(passphrase_key: &[u8], version: u32) -> Result<(Self, Vec<u8>), Box<dyn Error>> {
+        let mut key = vec![0u8; KEY_LEN];
+        rand_bytes(&mut key)?;
+        Ok((StoredKey::wrap(passphrase_key, version, &key)?, key))
+    }
+
+    fn wrap(passphrase_key: &[u8], version: u32, key: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(StoredKey {
+            version,
+            id: STANDARD.encode(key_id(key)),
+            wrapped: STANDARD.encode(seal(passphrase_key, key)?),
+        })
+    }
+
+    fn unwrap(&self, passphrase_key: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Sealed::parse(&STANDARD.decode(&self.wrapped)?)?.open(passphrase_key)
+    }
+}
+
+/// Unwrap the master keys, creating the key file with a first master key if there is none yet
+pub(super) async fn open(passphrase_key: &[u8]) -> Result<(u32, Vec<(u32, Vec<u8>)>), Box<dyn Error>> {
+    let Some(key_file) = load_key_file().await? else {
+        let (stored, key) = StoredKey::generate(passphrase_key, 1)?;
+        save_key_file(&KeyFile { current_version: 1, master_keys: vec![stored] }).await?;
+        return Ok((1, vec![(1, key)]));
+    };
+
+    let master_keys = key_file
+        .master_keys
+        .iter()
+        .map(|stored| Ok((stored.version, stored.unwrap(passphrase_key)?)))
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+    Ok((key_file.current_version, master_keys))
+}
+
+/// Add a new master key version and make it current. Older versions stay in the key file
+/// until `retire_old_master_keys`, so manifests that haven't been rewrapped yet remain readable.
+pub async fn rotate_master_key() -> Result<u32, Box<dyn Error>> {
+    let passphrase_key = keyring()
+        .passphrase_key
+        .clone()
+        .ok_or("The encryption key is locked. Enter your passphrase first.")?;
+    let mut key_file = load_key_file().await?.ok_or("There is no key file to rotate.")?;
+
+    let version = key_file.master_keys.iter().map(|stored| stored.version).max().unwrap_or(0) + 1;
+    let (stored, key) = StoredKey::generate(&passphrase_key, version)?;
+    key_file.master_keys.push(stored);
+    key_file.current_version = version;
+    save_key_file(&key_file).await?;
+
+    let mut keyring = keyring();
+    keyring.master_keys.push((version, key));
+    keyring.current_version = version;
+    Ok(version)
+}
+
+/// Drop every master key version but the current one, once nothing is wrapped by them anymore
+pub async fn retire_old_master_keys() -> Result<(), Box<dyn Error>> {
+    let mut key_file = load_key_file().await?.ok_or("There is no key file.")?;
+    let current_version = key_file.current_version;
+    key_file.master_keys.retain(|stored| stored.version == current_version);
+    save_key_file(&key_file).await?;
+
+    keyring().master_keys.retain(|(version, _)| *version == current_version);
+    Ok(())
+}
+
+/// Check a recovered master key against the key file in storage, if there is one.
+/// Recovery keeps a single version, so it is refused while a rotation is unfinished.
+pub(super) async fn verify_recovered(version: u32, key: &[u8]) -> Result<(), Box<dyn Error>> {
+    let Some(key_file) = load_key_file().await? else {
+        return Ok(());
+    };
+    if key_file.master_keys.len() > 1 {
+        return Err("Some snapshots still use an older master key, which one recovery code can't restore. Finish `key rotate` first.".into());
+    }
+    let stored = key_file.master_keys.iter().find(|stored| stored.version == version);
+    match stored {
+        Some(stored) if stored.id == STANDARD.encode(key_id(key)) => Ok(()),
+        Some(_) => Err("The recovery code does not match the key file in storage.".into()),
+        None => Err(format!("The key file in storage has no master key version {}.", version).into()),
+    }
+}
+
+/// Replace the key file with a single recovered master key, wrapped under a new passphrase key.
+/// `verify_recovered` makes sure no other version is lost with it.
+pub(super) async fn replace(passphrase_key: &[u8], version: u32, key: &[u8]) -> Result<(), Box<dyn Error>> {
+    let stored = StoredKey::wrap(passphrase_key, version, key)?;
+    save_key_file(&KeyFile { current_version: version, master_keys: vec![stored] }).await
+}
+
+async fn load_key_file() -> Result<Option<KeyFile>, Box<dyn Error>> {
+    match load_synced(REMOTE_KEY_FILE, KEY_FILE).await? {
+        Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        None => Ok(None),
+    }
+}
+
+async fn save_key_file(key_file: &KeyFile) -> Result<(), Box<dyn Error>> {
+    save_synced(REMOTE_KEY_FILE, KEY_FILE, &serde_json::to_vec_pretty(key_file)?).await
+}
@@ -1,49 +1,394 @@
-use openssl::symm::{Cipher, Crypter, Mode};
-use base64::decode;
-use dotenv::var;
+use openssl::rand::rand_bytes;
+use openssl::sha::Sha256;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use crate::config::config_dir;
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
-pub fn encrypt_data(data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let key = decode(var("ENCRYPTION_KEY")?)?;
-    let iv = decode(var("ENCRYPTION_IV")?)?;
+mod keyfile;
+mod passphrase;
+mod recovery;
 
-    // Ensure that key and IV have the correct length
-    if key.len() != 32 {
-        return Err("Invalid key length. Expected 32 bytes for AES-256.".into());
+pub use keyfile::{retire_old_master_keys, rotate_master_key};
+pub use recovery::{export_recovery_code, recover};
+
+// Header of every encrypted object:
+//   magic (4) | version (1) | algorithm (1) | key ID (16) | nonce (12)
+// followed by the ciphertext and the 16-byte authentication tag.
+// The header is authenticated as associated data, so none of it can be altered unnoticed.
+const MAGIC: &[u8; 4] = b"CNTE";
+const VERSION: u8 = 1;
+const ALGORITHM_AES_256_GCM: u8 = 1;
+const KEY_ID_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 2 + KEY_ID_LEN + NONCE_LEN;
+const KEY_LEN: usize = 32;
+
+// Key hierarchy: passphrase -> passphrase key -> master keys -> data keys -> chunks.
+// The master keys live in the key file, wrapped by the passphrase key. Each snapshot gets a random
+// data key, wrapped by the current master key and listed in its manifest. Manifests themselves are
+// encrypted under the master key, so a rotation rewrites the key file and manifests but no chunks.
+struct Keyring {
+    passphrase_key: Option<Vec<u8>>, // Only wraps master keys in the key file, never data
+    master_keys: Vec<(u32, Vec<u8>)>, // Paired with their version
+    current_version: u32,
+    data_keys: Vec<Vec<u8>>,
+}
+
+// Keys unlocked so far, kept in memory for the rest of the session
+static KEYRING: Mutex<Keyring> = Mutex::new(Keyring {
+    passphrase_key: None,
+    master_keys: Vec::new(),
+    current_version: 0,
+    data_keys: Vec::new(),
+});
+
+fn keyring() -> MutexGuard<'static, Keyring> {
+    KEYRING.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Keyring {
+    fn master_key(&self, version: u32) -> Result<&[u8], Box<dyn Error>> {
+        if self.master_keys.is_empty() {
+            return Err("The encryption key is locked. Enter your passphrase first.".into());
+        }
+        self.master_keys
+            .iter()
+            .find(|(v, _)| *v == version)
+            .map(|(_, key)| key.as_slice())
+            .ok_or_else(|| format!("Master key version {} is not in the key file.", version).into())
+    }
+}
+
+/// A data key as recorded in a manifest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WrappedKey {
+    pub id: String,       // Base64 key ID, as found in the header of every object it encrypted
+    pub key_version: u32, // Version of the master key that wrapped it
+    pub wrapped: String,  // Base64 data key, encrypted under that master key
+}
+
+/// A random key for the objects of one snapshot
+pub struct DataKey {
+    key: Vec<u8>,
+    wrapped: WrappedKey,
+}
+
+impl DataKey {
+    pub fn generate() -> Result<Self, Box<dyn Error>> {
+        let mut key = vec![0u8; KEY_LEN];
+        rand_bytes(&mut key)?;
+        let mut keyring = keyring();
+        let wrapped = wrap(&keyring, &key)?;
+        keyring.data_keys.push(key.clone());
+        Ok(DataKey { key, wrapped })
+    }
+
+    /// The form of this key that is stored in the manifest
+    pub fn wrapped(&self) -> &WrappedKey {
+        &self.wrapped
+    }
+
+    /// Encrypt data with AES-256-GCM under this key and a fresh random nonce
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        seal(&self.key, data)
+    }
+}
+
+/// Ask for the passphrase and open the key file, once per session
+pub async fn unlock() -> Result<(), Box<dyn Error>> {
+    if is_unlocked() {
+        return Ok(());
     }
-    if iv.len() != 16 {
-        return Err("Invalid IV length. Expected 16 bytes.".into());
+    let passphrase_key = passphrase::passphrase_key().await?;
+    let (current_version, master_keys) = keyfile::open(&passphrase_key).await?;
+
+    let mut keyring = keyring();
+    keyring.passphrase_key = Some(passphrase_key);
+    keyring.master_keys = master_keys;
+    keyring.current_version = current_version;
+    Ok(())
+}
+
+/// Unlock with a random master key instead of a passphrase, shared by every test in the process
+#[cfg(test)]
+pub fn unlock_for_tests() {
+    let mut keyring = keyring();
+    if keyring.master_keys.is_empty() {
+        let mut key = vec![0u8; KEY_LEN];
+        rand_bytes(&mut key).unwrap();
+        keyring.master_keys.push((1, key));
+        keyring.current_version = 1;
     }
+}
 
-    let cipher = Cipher::aes_256_cbc();
-    let mut crypter = Crypter::new(cipher, Mode::Encrypt, &key, Some(&iv))?;
-    let mut ciphertext = vec![0; data.len() + cipher.block_size()];
-    
-    let count = crypter.update(data, &mut ciphertext)?;
-    let rest = crypter.finalize(&mut ciphertext[count..])?;
-    ciphertext.truncate(count + rest);
+/// Whether the passphrase has been entered in this session
+pub fn is_unlocked() -> bool {
+    !keyring().master_keys.is_empty()
+}
 
-    Ok(ciphertext)
+/// Encrypt data with AES-256-GCM under the current master key and a fresh random nonce
+pub fn encrypt_data(data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let keyring = keyring();
+    seal(keyring.master_key(keyring.current_version)?, data)
 }
 
-pub fn decrypt_data(ciphertext: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let key = decode(var("ENCRYPTION_KEY")?)?;
-    let iv = decode(var("ENCRYPTION_IV")?)?;
+/// Decrypt data written under any key unlocked so far
+pub fn decrypt_data(ciphertext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let sealed = Sealed::parse(ciphertext)?;
+    sealed.open(&find_key(sealed.key_id)?)
+}
+
+/// Base64 ID of the key an object was encrypted with, or None for data without a valid header
+pub fn object_key_id(ciphertext: &[u8]) -> Option<String> {
+    Sealed::parse(ciphertext).ok().map(|sealed| STANDARD.encode(sealed.key_id))
+}
 
-    // Ensure that key and IV have the correct length
-    if key.len() != 32 {
+/// Encrypt data under a caller-provided 32-byte key, for secrets kept apart from the backup keys
+pub fn encrypt_with_key(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if key.len() != KEY_LEN {
         return Err("Invalid key length. Expected 32 bytes for AES-256.".into());
     }
-    if iv.len() != 16 {
-        return Err("Invalid IV length. Expected 16 bytes.".into());
+    seal(key, data)
+}
+
+/// Decrypt data written by `encrypt_with_key`
+pub fn decrypt_with_key(key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    Sealed::parse(ciphertext)?.open(key)
+}
+
+/// Unwrap the data keys listed in a manifest, so the chunks of that snapshot can be decrypted
+pub fn load_data_keys(keys: &[WrappedKey]) -> Result<(), Box<dyn Error>> {
+    let mut keyring = keyring();
+    for wrapped in keys {
+        let key = unwrap(&keyring, wrapped)?;
+        if !keyring.data_keys.contains(&key) {
+            keyring.data_keys.push(key);
+        }
     }
+    Ok(())
+}
+
+/// A random key wrapped under the current master key, for secrets other than data keys
+pub fn generate_wrapped_key() -> Result<WrappedKey, Box<dyn Error>> {
+    let mut key = vec![0u8; KEY_LEN];
+    rand_bytes(&mut key)?;
+    wrap(&keyring(), &key)
+}
 
-    let cipher = Cipher::aes_256_cbc();
-    let mut decrypter = Crypter::new(cipher, Mode::Decrypt, &key, Some(&iv))?;
-    let mut decrypted_data = vec![0; ciphertext.len() + cipher.block_size()];
+/// The plaintext of a key wrapped by `generate_wrapped_key`
+pub fn unwrap_key(wrapped: &WrappedKey) -> Result<Vec<u8>, Box<dyn Error>> {
+    unwrap(&keyring(), wrapped)
+}
 
-    let count = decrypter.update(ciphertext, &mut decrypted_data)?;
-    let rest = decrypter.finalize(&mut decrypted_data[count..])?;
-    decrypted_data.truncate(count + rest);
+/// Wrap keys again under the current master key, as part of a rotation
+pub fn rewrap_keys(keys: &[WrappedKey]) -> Result<Vec<WrappedKey>, Box<dyn Error>> {
+    let keyring = keyring();
+    keys.iter()
+        .map(|wrapped| wrap(&keyring, &unwrap(&keyring, wrapped)?))
+        .collect()
+}
 
-    Ok(decrypted_data)
+fn wrap(keyring: &Keyring, key: &[u8]) -> Result<WrappedKey, Box<dyn Error>> {
+    let master_key = keyring.master_key(keyring.current_version)?;
+    Ok(WrappedKey {
+        id: STANDARD.encode(key_id(key)),
+        key_version: keyring.current_version,
+        wrapped: STANDARD.encode(seal(master_key, key)?),
+    })
+}
+
+fn unwrap(keyring: &Keyring, wrapped: &WrappedKey) -> Result<Vec<u8>, Box<dyn Error>> {
+    let master_key = keyring.master_key(wrapped.key_version)?;
+    let key = Sealed::parse(&STANDARD.decode(&wrapped.wrapped)?)?.open(master_key)?;
+    if STANDARD.encode(key_id(&key)) != wrapped.id {
+        return Err("A wrapped data key does not match its ID.".into());
+    }
+    Ok(key)
+}
+
+/// Encrypt data under a specific 32-byte key
+fn seal(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce)?;
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(VERSION);
+    header.push(ALGORITHM_AES_256_GCM);
+    header.extend_from_slice(&key_id(key));
+    header.extend_from_slice(&nonce);
+
+    let mut tag = [0u8; TAG_LEN];
+    let encrypted = encrypt_aead(Cipher::aes_256_gcm(), key, Some(&nonce), &header, data, &mut tag)?;
+
+    let mut output = header;
+    output.extend_from_slice(&encrypted);
+    output.extend_from_slice(&tag);
+    Ok(output)
+}
+
+/// The parts of data written by `seal`
+struct Sealed<'a> {
+    header: &'a [u8],
+    key_id: &'a [u8],
+    nonce: &'a [u8],
+    encrypted: &'a [u8],
+    tag: &'a [u8],
+}
+
+impl<'a> Sealed<'a> {
+    fn parse(ciphertext: &'a [u8]) -> Result<Self, Box<dyn Error>> {
+        if !ciphertext.starts_with(MAGIC) {
+            return Err("Encrypted data has no header.".into());
+        }
+        if ciphertext.len() < HEADER_LEN + TAG_LEN {
+            return Err("Encrypted data is truncated.".into());
+        }
+
+        let (header, rest) = ciphertext.split_at(HEADER_LEN);
+        let version = header[MAGIC.len()];
+        let algorithm = header[MAGIC.len() + 1];
+        if version != VERSION || algorithm != ALGORITHM_AES_256_GCM {
+            return Err(format!("Unsupported encryption format: version {}, algorithm {}", version, algorithm).into());
+        }
+        let (encrypted, tag) = rest.split_at(rest.len() - TAG_LEN);
+        Ok(Sealed {
+            header,
+            key_id: &header[MAGIC.len() + 2..MAGIC.len() + 2 + KEY_ID_LEN],
+            nonce: &header[HEADER_LEN - NONCE_LEN..],
+            encrypted,
+            tag,
+        })
+    }
+
+    fn open(&self, key: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        decrypt_aead(Cipher::aes_256_gcm(), key, Some(self.nonce), self.header, self.encrypted, self.tag)
+            .map_err(|_| "Decryption failed: the data was tampered with or the key is wrong.".into())
+    }
+}
+
+/// Identifies the key an object was encrypted with, without revealing anything about the key
+fn key_id(key: &[u8]) -> [u8; KEY_ID_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"continu key id");
+    hasher.update(key);
+    let mut id = [0u8; KEY_ID_LEN];
+    id.copy_from_slice(&hasher.finish()[..KEY_ID_LEN]);
+    id
+}
+
+// Load key material that is kept both in storage and in the config directory. The copy in storage is
+// authoritative, so a rotation or `key recover` on one machine reaches the others. A failed lookup
+// without a local copy is an error rather than "not found", since starting over would lock the user
+// out of older backups.
+async fn load_synced(remote: &str, local: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let path = config_dir()?.join(local);
+    match storage::backend()?.exists(remote).await {
+        Ok(true) => {
+            let data = storage::backend()?.get(remote).await?;
+            fs::create_dir_all(config_dir()?)?;
+            fs::write(&path, &data)?;
+            Ok(Some(data))
+        }
+        // Offline, or the copy in storage went missing: the local copy still unlocks every backup
+        Ok(false) | Err(_) if path.exists() => Ok(Some(fs::read(path)?)),
+        Ok(false) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn save_synced(remote: &str, local: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    storage::backend()?.put(remote, data, true).await?;
+    fs::create_dir_all(config_dir()?)?;
+    fs::write(config_dir()?.join(local), data)?;
+    Ok(())
+}
+
+// Look up the key an object names in its header: a data key for chunks, a master key for manifests
+fn find_key(id: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let keyring = keyring();
+    let unlocked = keyring
+        .data_keys
+        .iter()
+        .chain(keyring.master_keys.iter().map(|(_, key)| key))
+        .find(|key| key_id(key) == id);
+    if let Some(key) = unlocked {
+        return Ok(key.clone());
+    }
+    if keyring.master_keys.is_empty() {
+        return Err("The encryption key is locked. Enter your passphrase first.".into());
+    }
+    Err("This data was encrypted with a different key.".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_key() -> Vec<u8> {
+        let mut key = vec![0u8; KEY_LEN];
+        rand_bytes(&mut key).unwrap();
+        key
+    }
+
+    #[test]
+    fn sealed_data_opens_with_its_key() {
+        let key = random_key();
+        let sealed = seal(&key, b"ssh-ed25519 AAAA").unwrap();
+        let parsed = Sealed::parse(&sealed).unwrap();
+        assert_eq!(parsed.key_id, key_id(&key));
+        assert_eq!(parsed.open(&key).unwrap(), b"ssh-ed25519 AAAA");
+        assert_eq!(object_key_id(&sealed), Some(STANDARD.encode(key_id(&key))));
+    }
+
+    #[test]
+    fn sealing_twice_uses_fresh_nonces() {
+        let key = random_key();
+        assert_ne!(seal(&key, b"same").unwrap(), seal(&key, b"same").unwrap());
+    }
+
+    #[test]
+    fn sealed_data_does_not_open_with_another_key() {
+        let sealed = seal(&random_key(), b"secret").unwrap();
+        assert!(Sealed::parse(&sealed).unwrap().open(&random_key()).is_err());
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let key = random_key();
+        let sealed = seal(&key, b"secret").unwrap();
+        // The ciphertext, the tag and the header are all authenticated
+        for position in [HEADER_LEN, sealed.len() - 1, HEADER_LEN - 1] {
+            let mut tampered = sealed.clone();
+            tampered[position] ^= 1;
+            assert!(Sealed::parse(&tampered).unwrap().open(&key).is_err());
+        }
+    }
+
+    #[test]
+    fn malformed_data_is_rejected() {
+        let sealed = seal(&random_key(), b"secret").unwrap();
+        assert!(Sealed::parse(b"plain text without a header").is_err());
+        assert!(Sealed::parse(&sealed[..HEADER_LEN + TAG_LEN - 1]).is_err());
+
+        let mut unsupported = sealed.clone();
+        unsupported[MAGIC.len()] = VERSION + 1;
+        assert!(Sealed::parse(&unsupported).is_err());
+        assert_eq!(object_key_id(b"no header"), None);
+    }
+
+    #[test]
+    fn caller_keys_must_be_32_bytes() {
+        assert!(encrypt_with_key(&[0u8; 16], b"data").is_err());
+        let key = random_key();
+        let encrypted = encrypt_with_key(&key, b"data").unwrap();
+        assert_eq!(decrypt_with_key(&key, &encrypted).unwrap(), b"data");
+    }
 }
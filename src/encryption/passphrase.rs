@@ -0,0 +1,157 @@
+use super::{load_synced, save_synced};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use inquire::Password;
+use openssl::pkcs5::scrypt;
+use openssl::rand::rand_bytes;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+const PARAMS_FILE: &str = "kdf.json";
+// A copy of the parameters lives next to the backups, so a new machine derives the same key
+const REMOTE_PARAMS_KEY: &str = "keys/kdf.json";
+const PASSPHRASE_ENV: &str = "CONTINU_PASSPHRASE";
+
+// scrypt with N = 2^15, r = 8, p = 1 needs 32 MiB of memory per guess
+const LOG_N: u8 = 15;
+const R: u32 = 8;
+const P: u32 = 1;
+const MAX_MEMORY: u64 = 64 * 1024 * 1024;
+const SALT_LEN: usize = 16;
+
+/// Salt and cost parameters the passphrase key is derived with. None of it is secret.
+#[derive(Serialize, Deserialize)]
+pub(super) struct KdfParams {
+    algorithm: String,
+    salt: String, // Base64
+    log_n: u8,
+    r: u32,
+    p: u32,
+    key_id: String, // Base64 ID of the derived key, to tell a wrong passphrase from corrupt data
+}
+
+impl KdfParams {
+    fn generate() -> Result<Self, Box<dyn Error>> {
+        let mut salt = [0u8; SALT_LEN];
+        rand_bytes(&mut salt)?;
+        Ok(KdfParams {
+            algorithm: "scrypt".to_string(),
+            salt: STANDARD.encode(salt),
+            log_n: LOG_N,
+            r: R,
+            p: P,
+            key_id: String::new(),
+        })
+    }
+
+    fn derive(&self, passphrase: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        if self.algorithm != "scrypt" {
+            return Err(format!("Unsupported key derivation: {}", self.algorithm).into());
+        }
+        // The parameters come from storage, so they're checked before they can shift out of range
+        // or ask for more memory than any machine here would use
+        let n = 1u64.checked_shl(self.log_n as u32).filter(|n| *n > 1);
+        let memory = n.and_then(|n| 128u64.checked_mul(self.r as u64)?.checked_mul(n.checked_add(self.p as u64)?));
+        if self.r == 0 || self.p == 0 || memory.is_none_or(|memory| memory > MAX_MEMORY) {
+            return Err(format!(
+                "Unsupported scrypt parameters: log_n = {}, r = {}, p = {}",
+                self.log_n, self.r, self.p
+            )
+            .into());
+        }
+        let salt = STANDARD.decode(&self.salt)?;
+        let mut key = vec![0u8; super::KEY_LEN];
+        scrypt(
+            passphrase.as_bytes(),
+            &salt,
+            1u64 << self.log_n,
+            self.r as u64,
+            self.p as u64,
+            MAX_MEMORY,
+            &mut key,
+        )?;
+        Ok(key)
+    }
+}
+
+/// Ask for the passphrase and derive the key that wraps the master keys.
+/// The first run on an account chooses a new salt and publishes it with the backups.
+pub(super) async fn passphrase_key() -> Result<Vec<u8>, Box<dyn Error>> {
+    match load_params().await? {
+        Some(params) => {
+            let key = params.derive(&read_passphrase("Enter your encryption passphrase:", false)?)?;
+            if STANDARD.encode(super::key_id(&key)) != params.key_id {
+                return Err("Incorrect passphrase. If you forgot it, run `key recover` with your recovery code.".into());
+            }
+            Ok(key)
+        }
+        None => {
+            let (params, key) = choose_passphrase_key()?;
+            save_params(&params).await?;
+            Ok(key)
+        }
+    }
+}
+
+/// Ask for a new passphrase and derive its key with a fresh salt.
+/// Nothing is stored: the caller publishes the parameters with `save_params` once the key is in use.
+pub(super) fn choose_passphrase_key() -> Result<(KdfParams, Vec<u8>), Box<dyn Error>> {
+    println!("Choose a passphrase to encrypt your backups. Keep a recovery code (`key export`) in case you forget it.");
+    let mut params = KdfParams::generate()?;
+    let key = params.derive(&read_passphrase("Choose an encryption passphrase:", true)?)?;
+    params.key_id = STANDARD.encode(super::key_id(&key));
+    Ok((params, key))
+}
+
+// `CONTINU_PASSPHRASE` lets scheduled backups run without a terminal
+fn read_passphrase(message: &str, confirm: bool) -> Result<String, Box<dyn Error>> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let prompt = Password::new(message).with_display_mode(inquire::PasswordDisplayMode::Masked);
+    let passphrase = if confirm {
+        prompt.with_custom_confirmation_message("Confirm the passphrase:").prompt()?
+    } else {
+        prompt.without_confirmation().prompt()?
+    };
+    if passphrase.is_empty() {
+        return Err("The passphrase must not be empty.".into());
+    }
+    Ok(passphrase)
+}
+
+async fn load_params() -> Result<Option<KdfParams>, Box<dyn Error>> {
+    match load_synced(REMOTE_PARAMS_KEY, PARAMS_FILE).await? {
+        Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        None => Ok(None),
+    }
+}
+
+pub(super) async fn save_params(params: &KdfParams) -> Result<(), Box<dyn Error>> {
+    save_synced(REMOTE_PARAMS_KEY, PARAMS_FILE, &serde_json::to_vec_pretty(params)?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(log_n: u8, r: u32, p: u32) -> KdfParams {
+        KdfParams { log_n, r, p, ..KdfParams::generate().unwrap() }
+    }
+
+    #[test]
+    fn out_of_range_parameters_are_refused() {
+        for (log_n, r, p) in [(64, R, P), (255, R, P), (0, R, P), (LOG_N, 0, P), (LOG_N, R, 0), (17, R, P), (LOG_N, u32::MAX, P)] {
+            assert!(params(log_n, r, p).derive("passphrase").is_err(), "log_n = {log_n}, r = {r}, p = {p}");
+        }
+    }
+
+    #[test]
+    fn the_same_passphrase_derives_the_same_key() {
+        let params = params(4, 1, 1);
+        let key = params.derive("passphrase").unwrap();
+        assert_eq!(key.len(), super::super::KEY_LEN);
+        assert_eq!(params.derive("passphrase").unwrap(), key);
+        assert_ne!(params.derive("another passphrase").unwrap(), key);
+    }
+}
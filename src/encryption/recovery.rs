@@ -0,0 +1,123 @@
+use super::{keyfile, keyring, passphrase, KEY_LEN};
+use data_encoding::BASE32_NOPAD;
+use openssl::sha::sha256;
+use std::error::Error;
+
+// Recovery code layout: format (1) | master key version (4) | master key (32) | checksum (4),
+// written as base32 in groups of five characters so it can be copied onto paper by hand
+const CODE_FORMAT: u8 = 1;
+const CHECKSUM_LEN: usize = 4;
+const CODE_LEN: usize = 1 + 4 + KEY_LEN + CHECKSUM_LEN;
+const GROUP_LEN: usize = 5;
+
+/// The current master key as a recovery code
+pub fn export_recovery_code() -> Result<String, Box<dyn Error>> {
+    let keyring = keyring();
+    if keyring.master_keys.len() > 1 {
+        return Err("Some snapshots still use an older master key. Finish `key rotate` first, so one code covers every snapshot.".into());
+    }
+
+    Ok(format_recovery_code(keyring.current_version, keyring.master_key(keyring.current_version)?))
+}
+
+// Encode a master key in the layout above
+fn format_recovery_code(version: u32, key: &[u8]) -> String {
+    let mut payload = vec![CODE_FORMAT];
+    payload.extend_from_slice(&version.to_be_bytes());
+    payload.extend_from_slice(key);
+    let checksum = sha256(&payload);
+    payload.extend_from_slice(&checksum[..CHECKSUM_LEN]);
+
+    let encoded = BASE32_NOPAD.encode(&payload);
+    let groups: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(GROUP_LEN)
+        .map(|group| std::str::from_utf8(group).unwrap_or_default())
+        .collect();
+    groups.chunks(6).map(|line| line.join(" ")).collect::<Vec<_>>().join("\n")
+}
+
+/// Restore the master key from a recovery code and protect it with a new passphrase.
+/// The key file and passphrase parameters in storage are replaced.
+pub async fn recover(code: &str) -> Result<(), Box<dyn Error>> {
+    let (version, key) = parse_recovery_code(code)?;
+    keyfile::verify_recovered(version, &key).await?;
+
+    // The key file goes first: until the new parameters are published, every machine still derives
+    // the old passphrase key, and a failed run can be repeated with the same code
+    let (params, passphrase_key) = passphrase::choose_passphrase_key()?;
+    keyfile::replace(&passphrase_key, version, &key).await?;
+    passphrase::save_params(&params).await?;
+
+    let mut keyring = keyring();
+    keyring.passphrase_key = Some(passphrase_key);
+    keyring.master_keys = vec![(version, key)];
+    keyring.current_version = version;
+    Ok(())
+}
+
+// Spaces, dashes and case don't matter, since the code is usually typed back in by hand
+fn parse_recovery_code(code: &str) -> Result<(u32, Vec<u8>), Box<dyn Error>> {
+    let normalized: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let payload = BASE32_NOPAD
+        .decode(normalized.as_bytes())
+        .map_err(|_| "The recovery code contains invalid characters.")?;
+    if payload.len() != CODE_LEN {
+        return Err("The recovery code is incomplete.".into());
+    }
+
+    let (data, checksum) = payload.split_at(CODE_LEN - CHECKSUM_LEN);
+    if sha256(data)[..CHECKSUM_LEN] != *checksum {
+        return Err("The recovery code has a typo.".into());
+    }
+    if data[0] != CODE_FORMAT {
+        return Err(format!("Unsupported recovery code format: {}", data[0]).into());
+    }
+    let version = u32::from_be_bytes(data[1..5].try_into()?);
+    Ok((version, data[5..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    #[test]
+    fn recovery_code_round_trips() {
+        let code = format_recovery_code(3, &KEY);
+        assert_eq!(parse_recovery_code(&code).unwrap(), (3, KEY.to_vec()));
+    }
+
+    #[test]
+    fn case_spaces_and_dashes_are_ignored() {
+        let code = format_recovery_code(1, &KEY).to_lowercase().replace(' ', "-").replace('\n', "  ");
+        assert_eq!(parse_recovery_code(&code).unwrap(), (1, KEY.to_vec()));
+    }
+
+    #[test]
+    fn typos_are_caught_by_the_checksum() {
+        let code = format_recovery_code(1, &KEY);
+        let typo = match code.as_bytes()[0] {
+            b'A' => code.replacen('A', "B", 1),
+            _ => format!("A{}", &code[1..]),
+        };
+        assert_eq!(parse_recovery_code(&typo).unwrap_err().to_string(), "The recovery code has a typo.");
+    }
+
+    #[test]
+    fn incomplete_or_invalid_codes_are_rejected() {
+        // 64 characters decode to one byte short of a full code
+        let code = format_recovery_code(1, &KEY).replace([' ', '\n'], "");
+        assert_eq!(
+            parse_recovery_code(&code[..64]).unwrap_err().to_string(),
+            "The recovery code is incomplete."
+        );
+        assert!(parse_recovery_code("not a code!").is_err());
+        assert!(parse_recovery_code("").is_err());
+    }
+}
@@ -1,19 +1,29 @@
 use chrono::Local;
 use std::fs::{OpenOptions, create_dir_all};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Logs go to `logs/` under the working directory unless redirected, as the tests do
+static LOG_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Write logs into `dir` for the rest of the process
+#[cfg(test)]
+pub fn set_log_dir(dir: PathBuf) {
+    let _ = LOG_DIR.set(dir);
+}
 
 /// Helper function to get the current date and create the log file path.
-fn log_file_path() -> String {
+fn log_file_path() -> PathBuf {
     let date = Local::now().format("%Y-%m-%d").to_string();
-    let log_dir = Path::new("logs");
+    let log_dir = LOG_DIR.get().map(PathBuf::as_path).unwrap_or(Path::new("logs"));
 
     // Create the log directory if it doesn't exist
     if !log_dir.exists() {
         create_dir_all(log_dir).unwrap();
     }
 
-    format!("logs/{}.log", date)
+    log_dir.join(format!("{}.log", date))
 }
 
 /// Helper function to write a log message with a timestamp.
@@ -37,7 +47,7 @@ pub fn write_log(message: &str) {
 
 /// Logs the backup progress
 pub fn log_progress(state: &crate::backup::BackupState) {
-    let progress = format!(
+    let mut progress = format!(
         "Progress: {:.2}% - {}/{} files completed. {}/{} bytes processed.",
         state.progress_percentage(),
         state.completed_files,
@@ -45,6 +55,9 @@ pub fn log_progress(state: &crate::backup::BackupState) {
         state.processed_size,
         state.total_size,
     );
+    if state.failed_files > 0 {
+        progress.push_str(&format!(" {} failed.", state.failed_files));
+    }
 
     write_log(&progress);
 }
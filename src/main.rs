@@ -1,15 +1,23 @@
+use clap::Parser;
 use colored::Colorize;
 use tokio::task;
 use dotenv::dotenv;
 use std::time::Duration;
 use crate::backup::backup_system;
 use crate::config::get_backup_frequency;
-use crate::auth::is_logged_in;
+use crate::auth::{can_back_up, is_logged_in};
+use crate::cli::Cli;
 use crate::cli::print_to_dashboard::print_to_dashboard_with_coordinates;
+use crate::logging::write_log;
+use crate::supabase::SupabaseError;
 
 mod auth;
 mod backup;
+mod chunking;
+mod compression;
 mod restore;
+mod snapshot;
+mod storage;
 mod cli;
 mod supabase;
 mod encryption;
@@ -22,10 +30,30 @@ static mut BACKUP_RUNNING: bool = false; // Track backup service status
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok(); // Load environment variables
 
+    // One-shot subcommands run and exit without starting the dashboard
+    let cli = Cli::parse();
+    if let Some(command) = &cli.command {
+        cli::handle_command(command).await;
+        return Ok(());
+    }
+
+    // A session whose refresh token was rejected is cleared here, so the menu offers to log in again
+    if storage::needs_login() && is_logged_in() {
+        let _ = auth::access_token().await;
+    }
+
+    // Ask for the passphrase up front so the backup service never prompts in the middle of the menu.
+    // Without it the dashboard still opens, with the backup service idle until the next start.
+    if can_back_up() {
+        if let Err(e) = encryption::unlock().await {
+            write_log(&format!("Backups stay locked: {}", e));
+        }
+    }
+
     // Spawn a background task for automatic backup based on frequency
-    let backup_service = task::spawn(async {
+    let _backup_service = task::spawn(async {
         loop {
-            if is_logged_in() {
+            if can_back_up() && encryption::is_unlocked() {
                 unsafe { BACKUP_RUNNING = true; }
                 let frequency = get_backup_frequency().unwrap_or_else(|_| "daily".to_string());
                 let interval = match frequency.as_str() {
@@ -34,12 +62,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     _ => Duration::from_secs(24 * 60 * 60), // Default is daily
                 };
 
-                // Run backup
-                backup_system().await.unwrap();
+                // Refresh the access token first; a refresh the server rejects logs the user out
+                let refreshed = if storage::needs_login() {
+                    auth::access_token().await.map(|_| ())
+                } else {
+                    Ok(())
+                };
+                if let Err(e) = refreshed {
+                    write_log(&format!("Skipping scheduled backup: {}", e));
+                    let wait = match e {
+                        SupabaseError::RateLimited { retry_after } => retry_after,
+                        _ => 60,
+                    };
+                    tokio::time::sleep(Duration::from_secs(wait)).await;
+                    continue;
+                }
+
+                // Run backup; a failed run is retried at the next interval rather than ending the service
+                if let Err(e) = backup_system().await {
+                    write_log(&format!("Scheduled backup failed: {}", e));
+                }
                 tokio::time::sleep(interval).await;
             } else {
                 unsafe { BACKUP_RUNNING = false; }
-                print_to_dashboard_with_coordinates("Please log in to start the backup service.".yellow().to_string().as_str(),0,12);
+                let message = if can_back_up() {
+                    "Backups are locked. Restart to enter your passphrase and start the backup service."
+                } else {
+                    "Please log in to start the backup service."
+                };
+                print_to_dashboard_with_coordinates(message.yellow().to_string().as_str(),0,12);
                 tokio::time::sleep(Duration::from_secs(60)).await; // Sleep before checking login again
             }
         }
@@ -0,0 +1,156 @@
+use crate::snapshot::{self, FileEntry};
+use colored::Colorize;
+use similar::TextDiff;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// How a backed-up file compares to the one currently on disk
+#[derive(Debug, PartialEq)]
+pub enum FileStatus {
+    Unchanged,
+    Modified,
+    MissingLocally,
+    NewerLocally, // Differs and was modified after the backup was taken
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self {
+            FileStatus::Unchanged => "unchanged".normal(),
+            FileStatus::Modified => "modified".yellow(),
+            FileStatus::MissingLocally => "missing locally".green(),
+            FileStatus::NewerLocally => "newer locally".red(),
+        };
+        write!(f, "{:<15}", label)
+    }
+}
+
+/// Compare a backed-up file with the live file at `local_path`, including its permissions
+pub fn compare(entry: &FileEntry, local_path: &Path) -> FileStatus {
+    let metadata = match fs::symlink_metadata(local_path) {
+        Ok(metadata) => metadata,
+        Err(_) => return FileStatus::MissingLocally,
+    };
+
+    let same_contents = match &entry.symlink_target {
+        Some(target) => fs::read_link(local_path)
+            .map(|local_target| local_target.to_string_lossy() == target.as_str())
+            .unwrap_or(false),
+        None => {
+            !metadata.file_type().is_symlink()
+                && metadata.len() == entry.size
+                && snapshot::content_hash(&fs::read(local_path).unwrap_or_default()) == entry.sha256
+        }
+    };
+    let same_mode = entry.symlink_target.is_some() || metadata.mode() & 0o7777 == entry.mode;
+
+    if same_contents && same_mode {
+        FileStatus::Unchanged
+    } else if metadata.mtime() > entry.mtime {
+        FileStatus::NewerLocally
+    } else {
+        FileStatus::Modified
+    }
+}
+
+/// Unified diff from the live file to the backed-up contents, or `None` when either side is not text
+pub fn unified_diff(local_path: &Path, backup_data: &[u8]) -> Option<String> {
+    let local_data = fs::read(local_path).unwrap_or_default();
+    let local_text = as_text(&local_data)?;
+    let backup_text = as_text(backup_data)?;
+
+    let diff = TextDiff::from_lines(local_text, backup_text);
+    Some(
+        diff.unified_diff()
+            .header(
+                &format!("{} (local)", local_path.display()),
+                &format!("{} (backup)", local_path.display()),
+            )
+            .to_string(),
+    )
+}
+
+// Treat data as text when it is valid UTF-8 without NUL bytes
+fn as_text(data: &[u8]) -> Option<&str> {
+    if data.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(data).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    // A backed-up regular file matching what is on disk right now
+    fn entry_for(path: &Path) -> FileEntry {
+        let path_str = path.to_str().unwrap();
+        FileEntry::new(path_str, &fs::metadata(path).unwrap(), &fs::read(path).unwrap(), Vec::new())
+    }
+
+    #[test]
+    fn files_are_compared_by_contents_mode_and_mtime() {
+        let scratch = storage::use_test_storage().join("diff-file");
+        fs::create_dir_all(&scratch).unwrap();
+        let path = scratch.join("app.conf");
+        fs::write(&path, b"port = 80\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let mut entry = entry_for(&path);
+        assert_eq!(compare(&entry, &path), FileStatus::Unchanged);
+
+        fs::write(&path, b"port = 81\n").unwrap();
+        let mtime = fs::metadata(&path).unwrap().mtime();
+        entry.mtime = mtime;
+        assert_eq!(compare(&entry, &path), FileStatus::Modified);
+        entry.mtime = mtime - 60;
+        assert_eq!(compare(&entry, &path), FileStatus::NewerLocally);
+
+        // Same contents, different permissions
+        let mut entry = entry_for(&path);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(compare(&entry, &path), FileStatus::Modified);
+        entry.mode = 0o600;
+        assert_eq!(compare(&entry, &path), FileStatus::Unchanged);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(compare(&entry, &path), FileStatus::MissingLocally);
+    }
+
+    #[test]
+    fn symlinks_are_compared_by_target() {
+        let scratch = storage::use_test_storage().join("diff-link");
+        fs::create_dir_all(&scratch).unwrap();
+        let link = scratch.join("app.link");
+        symlink("app.conf", &link).unwrap();
+        let metadata = fs::symlink_metadata(&link).unwrap();
+        let mut entry = FileEntry::new_symlink(link.to_str().unwrap(), &metadata, "app.conf");
+        entry.mtime = metadata.mtime();
+        assert_eq!(compare(&entry, &link), FileStatus::Unchanged);
+
+        entry.symlink_target = Some("other.conf".to_string());
+        assert_eq!(compare(&entry, &link), FileStatus::Modified);
+
+        // A regular file where the backup had a link
+        fs::remove_file(&link).unwrap();
+        fs::write(&link, b"app.conf").unwrap();
+        let mut entry = FileEntry::new_symlink(link.to_str().unwrap(), &metadata, "app.conf");
+        entry.mtime = i64::MAX;
+        assert_eq!(compare(&entry, &link), FileStatus::Modified);
+    }
+
+    #[test]
+    fn only_text_is_diffed() {
+        let scratch = storage::use_test_storage().join("diff-text");
+        fs::create_dir_all(&scratch).unwrap();
+        let path = scratch.join("app.conf");
+        fs::write(&path, b"port = 81\n").unwrap();
+
+        let diff = unified_diff(&path, b"port = 80\n").unwrap();
+        assert!(diff.contains("-port = 81\n+port = 80\n"));
+        assert_eq!(unified_diff(&path, b"\0binary"), None);
+    }
+}
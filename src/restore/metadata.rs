@@ -0,0 +1,98 @@
+use crate::snapshot::FileEntry;
+use nix::fcntl::AtFlags;
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{fchownat, Gid, Group, Uid, User};
+use std::error::Error;
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{symlink, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Atomically replace `path` with the backed-up contents and metadata of `entry`.
+/// The new file is fully prepared under a temporary name next to it, then renamed into place.
+pub fn write_file(entry: &FileEntry, path: &Path, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    let temp_path = temp_path_for(path);
+    let _ = fs::remove_file(&temp_path); // Left behind by an interrupted restore
+    let result = (|| -> Result<(), Box<dyn Error>> {
+        // Start private so sensitive contents are never readable with the default umask
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        apply_metadata(entry, &temp_path)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Atomically replace `path` with a symlink pointing at `target`
+pub fn write_symlink(entry: &FileEntry, path: &Path, target: &str) -> Result<(), Box<dyn Error>> {
+    let temp_path = temp_path_for(path);
+    let _ = fs::remove_file(&temp_path); // Left behind by an interrupted restore
+    let result = (|| -> Result<(), Box<dyn Error>> {
+        symlink(target, &temp_path)?;
+        apply_metadata(entry, &temp_path)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Reapply ownership, permissions and modification time recorded at backup time.
+/// Ownership is only changed when running as root, since nobody else may give files away.
+pub fn apply_metadata(entry: &FileEntry, path: &Path) -> Result<(), Box<dyn Error>> {
+    if Uid::effective().is_root() {
+        fchownat(
+            None,
+            path,
+            Some(resolve_uid(entry)),
+            Some(resolve_gid(entry)),
+            AtFlags::AT_SYMLINK_NOFOLLOW,
+        )?;
+    }
+
+    // Symlink permissions are meaningless on Linux; chmod after chown, which may clear setuid bits
+    if entry.symlink_target.is_none() {
+        fs::set_permissions(path, Permissions::from_mode(entry.mode))?;
+    }
+
+    utimensat(
+        None,
+        path,
+        &TimeSpec::UTIME_NOW,
+        &TimeSpec::new(entry.mtime, entry.mtime_nsec),
+        UtimensatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
+
+// Prefer the owner's name so files land with the right user even when uids differ between hosts
+fn resolve_uid(entry: &FileEntry) -> Uid {
+    match User::from_name(&entry.owner) {
+        Ok(Some(user)) => user.uid,
+        _ => Uid::from_raw(entry.owner.parse().unwrap_or(entry.uid)),
+    }
+}
+
+fn resolve_gid(entry: &FileEntry) -> Gid {
+    match Group::from_name(&entry.group) {
+        Ok(Some(group)) => group.gid,
+        _ => Gid::from_raw(entry.group.parse().unwrap_or(entry.gid)),
+    }
+}
+
+fn temp_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.continu-restore", file_name))
+}
@@ -1,5 +1,325 @@
-pub async fn restore_files() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Restore process initiated.");
-    // Placeholder for restore logic
+pub mod diff;
+pub mod metadata;
+pub mod packages;
+pub mod rollback;
+
+use crate::backup::BackupState;
+use crate::chunking::{self, ChunkNamer};
+use crate::logging::write_log;
+use crate::snapshot::{self, FileEntry};
+use rollback::Rollback;
+use chrono::{DateTime, Utc};
+use glob::{MatchOptions, Pattern};
+use nix::unistd::User;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Which snapshot to restore and which of its files
+#[derive(Default)]
+pub struct RestoreOptions {
+    pub snapshot_id: Option<String>,      // Restore this snapshot; takes precedence over `before`
+    pub before: Option<DateTime<Utc>>,    // Otherwise restore the newest snapshot taken before this time
+    pub paths: Vec<String>,               // Glob patterns limiting the restored files; empty restores everything
+    pub dry_run: bool,                    // Only report how the backup differs from the live files
+    pub show_diff: bool,                  // With `dry_run`, print a unified diff for changed text files
+    pub packages: bool,                   // Also reinstall packages from the backed-up dpkg selections
+    pub simulate: bool,                   // Let apt simulate the package installation instead of running it
+    pub target_root: Option<PathBuf>,     // Restore under this directory instead of `/`
+}
+
+impl RestoreOptions {
+    /// Where a backed-up file is restored to, after rewriting it under `target_root`
+    pub fn local_path(&self, entry: &FileEntry) -> PathBuf {
+        match &self.target_root {
+            Some(root) => root.join(entry.path.trim_start_matches('/')),
+            None => PathBuf::from(&entry.path),
+        }
+    }
+}
+
+// Main restore function: puts the selected files of a snapshot back where they came from
+pub async fn restore_files(options: &RestoreOptions) -> Result<(), Box<dyn Error>> {
+    write_log("Starting system restore...");
+
+    let snapshot_id = resolve_snapshot_id(options).await?;
+    let mut manifest = snapshot::fetch_manifest(&snapshot_id).await?;
+    write_log(&format!(
+        "Restoring snapshot {} taken on {} ({}) at {}",
+        manifest.snapshot_id, manifest.host, manifest.os_name, manifest.created_at
+    ));
+
+    let namer = ChunkNamer::for_manifest(&manifest)?;
+    let patterns = compile_patterns(&options.paths)?;
+    manifest.files.retain(|entry| matches_any(&patterns, &entry.path));
+    if manifest.files.is_empty() && !options.packages {
+        return Err("No files in the snapshot match the given paths.".into());
+    }
+
+    if options.dry_run {
+        preview_files(&manifest.files, &namer, options).await?;
+    } else {
+        restore_snapshot_files(&manifest.files, &namer, options).await?;
+    }
+
+    if options.packages {
+        let entry = manifest.packages.as_ref().ok_or("This backup has no package list.")?;
+        let selections = fetch_file_data(entry, &namer).await?;
+        packages::restore_packages(&String::from_utf8(selections)?, options.simulate, options.dry_run)?;
+    }
+
+    Ok(())
+}
+
+// Write every file back to its original location, reporting progress per file
+async fn restore_snapshot_files(files: &[FileEntry], namer: &ChunkNamer, options: &RestoreOptions) -> Result<(), Box<dyn Error>> {
+    let total_size = files.iter().map(|entry| entry.size).sum();
+    let mut state = BackupState::new(files.len(), total_size);
+    let mut rollback = Rollback::begin()?;
+
+    for entry in files {
+        let local_path = options.local_path(entry);
+        // Never overwrite a file whose current version could not be saved for `--undo`
+        let result = match rollback.save(&local_path) {
+            Ok(()) => restore_file(entry, namer, &local_path).await,
+            Err(e) => Err(format!("Could not save the current version: {}", e).into()),
+        };
+        match result {
+            Ok(file_size) => {
+                write_log(&format!("Restored: {}", local_path.display()));
+                state.update_progress(file_size);
+            }
+            Err(e) => {
+                write_log(&format!("Failed to restore {}: {}", entry.path, e));
+                state.record_failure(entry.size);
+            }
+        }
+    }
+
+    rollback.discard_if_empty()?;
+
+    if state.failed_files > 0 {
+        return Err(format!(
+            "Restore finished with errors: {} of {} files failed.",
+            state.failed_files, state.total_files
+        )
+        .into());
+    }
+
+    write_log("Restore completed successfully.");
     Ok(())
 }
+
+// Pick the snapshot to restore: an explicit ID, the newest one before a point in time, or the latest
+async fn resolve_snapshot_id(options: &RestoreOptions) -> Result<String, Box<dyn Error>> {
+    if let Some(snapshot_id) = &options.snapshot_id {
+        if !snapshot::is_snapshot_id(snapshot_id) {
+            return Err(format!("Invalid snapshot ID: {}", snapshot_id).into());
+        }
+        return Ok(snapshot_id.clone());
+    }
+
+    // Backups come back newest first
+    let backups = snapshot::fetch_snapshots().await?;
+    let backup = match options.before {
+        Some(before) => backups
+            .into_iter()
+            .find(|backup| backup.backup_date < before)
+            .ok_or_else(|| format!("No backups found before {}.", before))?,
+        None => backups.into_iter().next().ok_or("No backups found.")?,
+    };
+    Ok(backup.snapshot_id)
+}
+
+// Compile the path globs, expanding a leading `~` to the home directory
+fn compile_patterns(paths: &[String]) -> Result<Vec<Pattern>, Box<dyn Error>> {
+    let home = user_home();
+    paths
+        .iter()
+        .map(|path| {
+            let expanded = match path.strip_prefix("~/") {
+                Some(rest) => home.join(rest).to_string_lossy().into_owned(),
+                None => path.clone(),
+            };
+            Ok(Pattern::new(expanded.trim_end_matches('/'))?)
+        })
+        .collect()
+}
+
+// Restores usually run under sudo, where `~` should still mean the home of the user who ran it
+fn user_home() -> PathBuf {
+    std::env::var("SUDO_USER")
+        .ok()
+        .and_then(|name| User::from_name(&name).ok().flatten())
+        .map(|user| user.dir)
+        .or_else(dirs::home_dir)
+        .unwrap_or_default()
+}
+
+// A file matches when a pattern matches its path or one of its parent directories
+fn matches_any(patterns: &[Pattern], file_path: &str) -> bool {
+    if patterns.is_empty() {
+        return true;
+    }
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    patterns.iter().any(|pattern| {
+        Path::new(file_path)
+            .ancestors()
+            .any(|path| pattern.matches_path_with(path, options))
+    })
+}
+
+// Report what a restore would change without touching the filesystem
+async fn preview_files(files: &[FileEntry], namer: &ChunkNamer, options: &RestoreOptions) -> Result<(), Box<dyn Error>> {
+    for entry in files {
+        let local_path = options.local_path(entry);
+        let status = diff::compare(entry, &local_path);
+        println!("{} {}", status, local_path.display());
+
+        let changed = matches!(status, diff::FileStatus::Modified | diff::FileStatus::NewerLocally);
+        if options.show_diff && changed && entry.symlink_target.is_none() {
+            let file_data = fetch_file_data(entry, namer).await?;
+            match diff::unified_diff(&local_path, &file_data) {
+                Some(diff) => print!("{}", diff),
+                None => println!("Binary files differ."),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Download and decrypt the contents of a backed-up file, checking them against the manifest
+async fn fetch_file_data(entry: &FileEntry, namer: &ChunkNamer) -> Result<Vec<u8>, Box<dyn Error>> {
+    let file_data = chunking::download_chunks(&entry.chunks, namer).await?;
+    if snapshot::content_hash(&file_data) != entry.sha256 {
+        return Err("Contents do not match the hash recorded in the manifest.".into());
+    }
+    Ok(file_data)
+}
+
+// Download, decrypt and write back a single file with its metadata, returning its restored size
+async fn restore_file(entry: &FileEntry, namer: &ChunkNamer, path: &Path) -> Result<u64, Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match &entry.symlink_target {
+        Some(target) => {
+            metadata::write_symlink(entry, path, target)?;
+            Ok(0)
+        }
+        None => {
+            let file_data = fetch_file_data(entry, namer).await?;
+            metadata::write_file(entry, path, &file_data)?;
+            Ok(file_data.len() as u64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunking::ChunkUploader;
+    use crate::compression::{Codec, CompressionSettings};
+    use crate::encryption::{self, DataKey};
+    use crate::snapshot::Manifest;
+    use crate::storage;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    fn matches(paths: &[&str], file_path: &str) -> bool {
+        let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+        matches_any(&compile_patterns(&paths).unwrap(), file_path)
+    }
+
+    #[test]
+    fn no_patterns_match_everything() {
+        assert!(matches(&[], "/etc/environment"));
+    }
+
+    #[test]
+    fn patterns_match_files_and_their_directories() {
+        assert!(matches(&["/etc/environment"], "/etc/environment"));
+        assert!(matches(&["/etc"], "/etc/apt/sources.list"));
+        assert!(matches(&["/etc/apt/"], "/etc/apt/sources.list"));
+        assert!(!matches(&["/etc/ap"], "/etc/apt/sources.list"));
+        assert!(matches(&["/home/user/.vimrc", "/etc/apt"], "/etc/apt/sources.list"));
+    }
+
+    #[test]
+    fn wildcards_do_not_cross_directories() {
+        assert!(matches(&["/etc/*.list"], "/etc/sources.list"));
+        assert!(!matches(&["/etc/*.list"], "/etc/apt/sources.list"));
+        assert!(matches(&["/etc/**/*.list"], "/etc/apt/sources.list"));
+    }
+
+    #[test]
+    fn home_is_expanded() {
+        std::env::remove_var("SUDO_USER");
+        let home = dirs::home_dir().unwrap();
+        assert!(matches(&["~/.bashrc"], &home.join(".bashrc").to_string_lossy()));
+        assert!(!matches(&["~/.bashrc"], "/root/.bashrc.bak"));
+
+        // The only test that sets it, so it doesn't race with the others
+        std::env::set_var("SUDO_USER", "root");
+        let under_sudo = matches(&["~/.bashrc"], "/root/.bashrc");
+        std::env::remove_var("SUDO_USER");
+        assert!(under_sudo);
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        assert!(compile_patterns(&["/etc/[".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn restores_a_local_backup_under_the_target_root() {
+        let _rollbacks = rollback::TEST_LOCK.lock().await;
+        let scratch = storage::use_test_storage();
+        encryption::unlock_for_tests();
+
+        let source = scratch.join("restore-source/etc");
+        fs::create_dir_all(&source).unwrap();
+        let (file, link) = (source.join("app.conf"), source.join("app.link"));
+        let contents = b"listen = 127.0.0.1\n".repeat(500);
+        fs::write(&file, &contents).unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        symlink("app.conf", &link).unwrap();
+
+        // Back up both the way `backup_system` does, into the local test storage
+        let compression = CompressionSettings::new(Codec::Zstd, None);
+        let mut manifest = Manifest::new().unwrap();
+        manifest.snapshot_id = "20000101T000000Z".to_string();
+        let namer = ChunkNamer::for_manifest(&manifest).unwrap();
+        let mut uploader = ChunkUploader::new(None, false, compression, DataKey::generate().unwrap(), namer);
+        let chunks = uploader.upload(&contents).await.unwrap();
+        let (file_path, link_path) = (file.to_str().unwrap(), link.to_str().unwrap());
+        manifest.files.push(FileEntry::new(file_path, &fs::metadata(&file).unwrap(), &contents, chunks));
+        manifest.files.push(FileEntry::new_symlink(link_path, &fs::symlink_metadata(&link).unwrap(), "app.conf"));
+        (manifest.chunk_keys, manifest.data_keys) = uploader.chunk_keys(&manifest).unwrap();
+        snapshot::upload_manifest(&manifest, &compression).await.unwrap();
+
+        let target_root = scratch.join("restore-target");
+        let options = RestoreOptions {
+            snapshot_id: Some(manifest.snapshot_id.clone()),
+            target_root: Some(target_root.clone()),
+            ..Default::default()
+        };
+        restore_files(&options).await.unwrap();
+
+        let restored = target_root.join(file_path.trim_start_matches('/'));
+        assert_eq!(fs::read(&restored).unwrap(), contents);
+        assert_eq!(fs::metadata(&restored).unwrap().permissions().mode() & 0o7777, 0o640);
+        assert_eq!(fs::metadata(&restored).unwrap().modified().unwrap(), fs::metadata(&file).unwrap().modified().unwrap());
+        let restored_link = target_root.join(link_path.trim_start_matches('/'));
+        assert_eq!(fs::read_link(restored_link).unwrap(), Path::new("app.conf"));
+        // The originals are left alone
+        assert_eq!(fs::read(&file).unwrap(), contents);
+
+        let nothing = RestoreOptions { paths: vec!["/nonexistent".to_string()], ..options };
+        let error = restore_files(&nothing).await.unwrap_err();
+        assert_eq!(error.to_string(), "No files in the snapshot match the given paths.");
+    }
+}
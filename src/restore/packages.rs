@@ -0,0 +1,130 @@
+use crate::logging::write_log;
+use inquire::Confirm;
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Reinstall the packages from a backed-up `dpkg --get-selections` list that are missing on this host.
+/// With `simulate`, apt only reports what it would do; with `list_only`, nothing is run at all.
+pub fn restore_packages(selections: &str, simulate: bool, list_only: bool) -> Result<(), Box<dyn Error>> {
+    let installed = installed_packages()?;
+    let missing: Vec<String> = parse_selections(selections)
+        .into_iter()
+        .filter(|package| !installed.contains(package))
+        .collect();
+
+    if missing.is_empty() {
+        write_log("All backed-up packages are already installed.");
+        return Ok(());
+    }
+
+    println!("{} packages are missing on this host:", missing.len());
+    println!("{}", missing.join(" "));
+    if list_only {
+        return Ok(());
+    }
+
+    if simulate {
+        let mut args = vec!["install", "--simulate"];
+        args.extend(missing.iter().map(String::as_str));
+        return run("apt-get", &args);
+    }
+
+    let confirmed = Confirm::new(&format!("Install {} packages?", missing.len()))
+        .with_default(false)
+        .prompt()?;
+    if !confirmed {
+        write_log("Package restore cancelled.");
+        return Ok(());
+    }
+
+    write_log("Reinstalling missing packages...");
+    update_available_packages()?;
+    set_selections(&missing)?;
+    run("apt-get", &["dselect-upgrade", "--yes"])?;
+    write_log("Package restore completed successfully.");
+    Ok(())
+}
+
+// Package names selected for installation, skipping deinstall/purge entries
+fn parse_selections(selections: &str) -> Vec<String> {
+    selections
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(package), Some("install" | "hold")) => Some(package.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// Packages currently installed on this host, named the same way `dpkg --get-selections` names them
+fn installed_packages() -> Result<HashSet<String>, Box<dyn Error>> {
+    let output = Command::new("dpkg-query")
+        .args(["--show", "--showformat", "${binary:Package} ${db:Status-Status}\n"])
+        .output()?;
+    if !output.status.success() {
+        return Err("Failed to query installed packages.".into());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.strip_suffix(" installed"))
+        .map(str::to_string)
+        .collect())
+}
+
+// dpkg ignores selections for packages it has never heard of, so refresh its view of apt's package lists first
+fn update_available_packages() -> Result<(), Box<dyn Error>> {
+    run("apt-get", &["update"])?;
+    let available = Command::new("apt-cache").arg("dumpavail").output()?;
+    pipe_to("dpkg", &["--merge-avail"], &available.stdout)
+}
+
+// Mark the packages for installation so `apt-get dselect-upgrade` picks them up
+fn set_selections(packages: &[String]) -> Result<(), Box<dyn Error>> {
+    let selections: String = packages
+        .iter()
+        .map(|package| format!("{} install\n", package))
+        .collect();
+    pipe_to("dpkg", &["--set-selections"], selections.as_bytes())
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), Box<dyn Error>> {
+    let status = Command::new(program).args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} {} failed: {}", program, args.join(" "), status).into())
+    }
+}
+
+fn pipe_to(program: &str, args: &[&str], input: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut child = Command::new(program).args(args).stdin(Stdio::piped()).spawn()?;
+    child.stdin.take().ok_or("Failed to open stdin")?.write_all(input)?;
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} {} failed: {}", program, args.join(" "), status).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selections_keep_installed_and_held_packages() {
+        let selections = "bash\t\t\t\t\tinstall\nlibc6:amd64\t\t\t\thold\nvim\t\t\t\t\tdeinstall\nnano\t\t\t\t\tpurge\n\nincomplete\n";
+        assert_eq!(parse_selections(selections), vec!["bash", "libc6:amd64"]);
+    }
+
+    #[test]
+    fn empty_selections_select_nothing() {
+        assert!(parse_selections("").is_empty());
+    }
+}
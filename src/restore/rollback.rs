@@ -0,0 +1,194 @@
+use crate::config::data_dir;
+use crate::logging::write_log;
+use crate::restore::metadata;
+use crate::snapshot::FileEntry;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+const ROLLBACK_INDEX: &str = "rollback.json";
+const ROLLBACKS_KEPT: usize = 10;
+
+// Held by tests that create rollback points, so `undo_last_restore` finds the one it expects
+#[cfg(test)]
+pub static TEST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// A file as it was before a restore overwrote it
+#[derive(Serialize, Deserialize)]
+struct RollbackEntry {
+    path: String,
+    saved: Option<FileEntry>, // `None` when the file did not exist
+    #[serde(default)]
+    copy: Option<String>,     // Name of the saved contents inside the rollback directory
+}
+
+/// Local copies of every file a restore is about to overwrite, so the restore can be undone offline
+pub struct Rollback {
+    dir: PathBuf,
+    entries: Vec<RollbackEntry>,
+}
+
+impl Rollback {
+    /// Start a new rollback point for a restore that is about to run
+    pub fn begin() -> Result<Self, Box<dyn Error>> {
+        prune_old_rollbacks()?;
+        let dir = rollback_root()?.join(Local::now().format("%Y%m%dT%H%M%S%.3f").to_string());
+        // Saved copies may include secrets from /etc, so keep them private to the restoring user
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        Ok(Rollback { dir, entries: Vec::new() })
+    }
+
+    /// Save the current version of `path` before it is replaced.
+    /// The index is rewritten after every file so an interrupted restore can still be undone.
+    pub fn save(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let path_str = path.to_string_lossy().into_owned();
+        if self.entries.iter().any(|entry| entry.path == path_str) {
+            return Ok(());
+        }
+
+        let (saved, copy) = match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                let target = fs::read_link(path)?;
+                (Some(FileEntry::new_symlink(&path_str, &meta, &target.to_string_lossy())), None)
+            }
+            Ok(meta) => {
+                let contents = fs::read(path)?;
+                let copy_name = self.entries.len().to_string();
+                fs::write(self.dir.join(&copy_name), &contents)?;
+                (Some(FileEntry::new(&path_str, &meta, &contents, Vec::new())), Some(copy_name))
+            }
+            Err(_) => (None, None),
+        };
+
+        self.entries.push(RollbackEntry { path: path_str, saved, copy });
+        fs::write(self.dir.join(ROLLBACK_INDEX), serde_json::to_vec_pretty(&self.entries)?)?;
+        Ok(())
+    }
+
+    /// Drop the rollback point when the restore turned out not to change anything
+    pub fn discard_if_empty(self) -> Result<(), Box<dyn Error>> {
+        if self.entries.is_empty() {
+            fs::remove_dir_all(&self.dir)?;
+        } else {
+            write_log(&format!("Previous versions saved to {}", self.dir.display()));
+        }
+        Ok(())
+    }
+}
+
+/// Put back the files saved before the most recent restore, then forget that rollback point
+pub fn undo_last_restore() -> Result<(), Box<dyn Error>> {
+    let dir = latest_rollback()?.ok_or("There is no restore to undo.")?;
+    let entries: Vec<RollbackEntry> = serde_json::from_slice(&fs::read(dir.join(ROLLBACK_INDEX))?)?;
+    write_log(&format!("Undoing restore saved in {}", dir.display()));
+
+    let mut failed = 0;
+    for entry in entries.iter().rev() {
+        match undo_entry(&dir, entry) {
+            Ok(()) => write_log(&format!("Rolled back: {}", entry.path)),
+            Err(e) => {
+                write_log(&format!("Failed to roll back {}: {}", entry.path, e));
+                failed += 1;
+            }
+        }
+    }
+
+    // Keep the rollback point around if anything is still missing, so the undo can be retried
+    if failed > 0 {
+        return Err(format!("Undo finished with errors: {} of {} files failed.", failed, entries.len()).into());
+    }
+    fs::remove_dir_all(&dir)?;
+    write_log("Undo completed successfully.");
+    Ok(())
+}
+
+fn undo_entry(dir: &Path, entry: &RollbackEntry) -> Result<(), Box<dyn Error>> {
+    let path = Path::new(&entry.path);
+    match &entry.saved {
+        // The restore created this file, so undoing it means removing it again
+        None => match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        },
+        Some(saved) => match (&saved.symlink_target, &entry.copy) {
+            (Some(target), _) => metadata::write_symlink(saved, path, target),
+            (None, Some(copy)) => metadata::write_file(saved, path, &fs::read(dir.join(copy))?),
+            (None, None) => Err("The saved copy is missing.".into()),
+        },
+    }
+}
+
+// Rollback points live under the local data directory, e.g. /root/.local/share/continu/rollback
+fn rollback_root() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(data_dir()?.join("rollback"))
+}
+
+// Completed rollback points, oldest first
+fn rollback_dirs() -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let root = rollback_root()?;
+    if !root.exists() {
+        return Ok(Vec::new());
+    }
+    let mut dirs: Vec<PathBuf> = fs::read_dir(root)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.join(ROLLBACK_INDEX).exists())
+        .collect();
+    dirs.sort();
+    Ok(dirs)
+}
+
+fn latest_rollback() -> Result<Option<PathBuf>, Box<dyn Error>> {
+    Ok(rollback_dirs()?.pop())
+}
+
+fn prune_old_rollbacks() -> Result<(), Box<dyn Error>> {
+    let dirs = rollback_dirs()?;
+    if dirs.len() >= ROLLBACKS_KEPT {
+        for dir in &dirs[..=dirs.len() - ROLLBACKS_KEPT] {
+            fs::remove_dir_all(dir)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    #[test]
+    fn undo_puts_back_files_and_links_and_removes_new_files() {
+        let _rollbacks = TEST_LOCK.blocking_lock();
+        let scratch = storage::use_test_storage().join("rollback");
+        fs::create_dir_all(&scratch).unwrap();
+        let (file, link, new) = (scratch.join("app.conf"), scratch.join("app.link"), scratch.join("new.conf"));
+        fs::write(&file, b"before").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+        symlink("app.conf", &link).unwrap();
+
+        let mut rollback = Rollback::begin().unwrap();
+        for path in [&file, &link, &new] {
+            rollback.save(path).unwrap();
+        }
+        let dir = rollback.dir.clone();
+        rollback.discard_if_empty().unwrap();
+
+        // What a restore would do to them
+        fs::write(&file, b"after").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::remove_file(&link).unwrap();
+        symlink("elsewhere", &link).unwrap();
+        fs::write(&new, b"restored").unwrap();
+
+        undo_last_restore().unwrap();
+        assert_eq!(fs::read(&file).unwrap(), b"before");
+        assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o7777, 0o600);
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("app.conf"));
+        assert!(!new.exists());
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::compression::{self, CompressionSettings};
+use crate::encryption::{self, WrappedKey};
+use crate::storage::{self, StorageError};
+use crate::supabase::{self, BackupRecord};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use nix::unistd::{Gid, Group, Uid, User};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+
+/// A single backed-up file and the chunks its contents were stored as
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileEntry {
+    pub path: String,       // Original absolute path on the backed-up machine
+    pub size: u64,
+    pub mode: u32,          // Permission bits as returned by stat
+    pub owner: String,      // User name, or the numeric uid when it has no name
+    #[serde(default)]
+    pub group: String,      // Group name, or the numeric gid when it has no name
+    #[serde(default)]
+    pub uid: u32,           // Used when the owner name does not exist on the restoring host
+    #[serde(default)]
+    pub gid: u32,
+    pub mtime: i64,         // Seconds since the Unix epoch
+    #[serde(default)]
+    pub mtime_nsec: i64,
+    pub sha256: String,     // Hex digest of the plaintext contents
+    #[serde(default)]
+    pub chunks: Vec<String>, // Hashes of the chunks making up the contents, in order; empty for symlinks
+    #[serde(default)]
+    pub symlink_target: Option<String>,
+}
+
+impl FileEntry {
+    /// Entry for a regular file whose contents were uploaded as `chunks`
+    pub fn new(path: &str, metadata: &Metadata, contents: &[u8], chunks: Vec<String>) -> Self {
+        FileEntry::with_hash(path, metadata, content_hash(contents), chunks)
+    }
+
+    /// Entry for a regular file whose contents are already known by hash, e.g. from an earlier snapshot
+    pub fn with_hash(path: &str, metadata: &Metadata, sha256: String, chunks: Vec<String>) -> Self {
+        FileEntry {
+            path: path.to_string(),
+            size: metadata.len(),
+            mode: metadata.mode() & 0o7777,
+            owner: user_name(metadata.uid()),
+            group: group_name(metadata.gid()),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            sha256,
+            chunks,
+            symlink_target: None,
+        }
+    }
+
+    /// Entry for a symlink, which only records where it points
+    pub fn new_symlink(path: &str, metadata: &Metadata, target: &str) -> Self {
+        FileEntry {
+            size: 0,
+            sha256: content_hash(target.as_bytes()),
+            symlink_target: Some(target.to_string()),
+            ..FileEntry::new(path, metadata, &[], Vec::new())
+        }
+    }
+}
+
+/// Everything uploaded by one backup run, forming a consistent point in time
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub snapshot_id: String,
+    pub host: String,
+    pub os_name: String,
+    pub os_version: String,
+    pub created_at: DateTime<Utc>,
+    pub files: Vec<FileEntry>,
+    #[serde(default)]
+    pub packages: Option<FileEntry>, // The `dpkg --get-selections` output
+    #[serde(default)]
+    pub data_keys: Vec<WrappedKey>, // Keys of every chunk referenced, each with the master key version that wrapped it
+    #[serde(default)]
+    pub chunk_keys: BTreeMap<String, String>, // ID of the key each chunk is encrypted with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_key: Option<WrappedKey>, // Set when chunks are stored under opaque names rather than their hashes
+}
+
+impl Manifest {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let created_at = Utc::now();
+        let (os_name, os_version) = crate::config::get_os_details()?;
+
+        Ok(Manifest {
+            snapshot_id: created_at.format(SNAPSHOT_ID_FORMAT).to_string(),
+            host: sys_info::hostname()?,
+            os_name,
+            os_version,
+            created_at,
+            files: Vec::new(),
+            packages: None,
+            data_keys: Vec::new(),
+            chunk_keys: BTreeMap::new(),
+            name_key: None,
+        })
+    }
+
+    /// Entry for a path, if the snapshot contains it
+    pub fn file(&self, path: &str) -> Option<&FileEntry> {
+        self.files.iter().find(|entry| entry.path == path)
+    }
+
+    /// Every chunk the snapshot refers to
+    pub fn chunk_hashes(&self) -> impl Iterator<Item = &String> {
+        self.files.iter().chain(&self.packages).flat_map(|entry| &entry.chunks)
+    }
+
+    /// Combined size of all files in the snapshot
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|entry| entry.size).sum()
+    }
+}
+
+// Snapshot IDs are UTC timestamps so they sort chronologically
+const SNAPSHOT_ID_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Check whether a storage folder name is a snapshot ID
+pub fn is_snapshot_id(name: &str) -> bool {
+    NaiveDateTime::parse_from_str(name, SNAPSHOT_ID_FORMAT).is_ok()
+}
+
+/// Object key of the manifest for a snapshot
+pub fn manifest_key(snapshot_id: &str) -> String {
+    format!("{}{}/{}", SNAPSHOTS_PREFIX, snapshot_id, MANIFEST_NAME)
+}
+
+// Manifests sit apart from the chunks, so finding the snapshots doesn't list the whole store
+const SNAPSHOTS_PREFIX: &str = "snapshots/";
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Every snapshot of the user, newest first. Supabase keeps a row per snapshot in the backups table;
+/// other backends have no database, so their snapshots are found from the manifests in storage.
+pub async fn fetch_snapshots() -> Result<Vec<BackupRecord>, Box<dyn Error>> {
+    let backend = storage::backend()?;
+    if backend.needs_login() {
+        return Ok(supabase::fetch_backups().await?);
+    }
+    let mut backups: Vec<BackupRecord> = backend
+        .list(SNAPSHOTS_PREFIX)
+        .await?
+        .iter()
+        .filter_map(|key| key.strip_prefix(SNAPSHOTS_PREFIX)?.strip_suffix(MANIFEST_NAME)?.strip_suffix('/'))
+        .filter(|snapshot_id| is_snapshot_id(snapshot_id))
+        .map(|snapshot_id| BackupRecord {
+            snapshot_id: snapshot_id.to_string(),
+            host: None,
+            file_count: None,
+            file_size: None,
+            ciphertext_size: None,
+            backup_date: NaiveDateTime::parse_from_str(snapshot_id, SNAPSHOT_ID_FORMAT)
+                .map(|time| time.and_utc())
+                .unwrap_or_default(),
+        })
+        .collect();
+    backups.sort_by_key(|backup| Reverse(backup.backup_date));
+    Ok(backups)
+}
+
+/// Download and decrypt the manifest of a snapshot, unlocking the data keys of its chunks
+pub async fn fetch_manifest(snapshot_id: &str) -> Result<Manifest, Box<dyn Error>> {
+    let encrypted_manifest = storage::backend()?.get(&manifest_key(snapshot_id)).await?;
+    let manifest_data = compression::decompress(&encryption::decrypt_data(&encrypted_manifest)?)?;
+    let manifest: Manifest = serde_json::from_slice(&manifest_data)?;
+    encryption::load_data_keys(&manifest.data_keys)?;
+    Ok(manifest)
+}
+
+/// Compress, encrypt and upload the manifest of a new snapshot, returning its stored size
+pub async fn upload_manifest(manifest: &Manifest, compression: &CompressionSettings) -> Result<u64, Box<dyn Error>> {
+    let encrypted_manifest = seal_manifest(manifest, compression)?;
+    storage::backend()?.put(&manifest_key(&manifest.snapshot_id), &encrypted_manifest, false).await?;
+    Ok(encrypted_manifest.len() as u64)
+}
+
+/// Overwrite the manifest of an existing snapshot, e.g. after its data keys were rewrapped
+pub async fn replace_manifest(manifest: &Manifest, compression: &CompressionSettings) -> Result<(), Box<dyn Error>> {
+    let encrypted_manifest = seal_manifest(manifest, compression)?;
+    storage::backend()?.put(&manifest_key(&manifest.snapshot_id), &encrypted_manifest, true).await?;
+    Ok(())
+}
+
+// Every data key is also stored on its own, so a chunk found in storage can be matched to its key
+// even after the snapshots that listed the key stopped carrying it
+const DATA_KEYS_PREFIX: &str = "keys/data/";
+
+fn data_key_object(id: &str) -> Result<String, Box<dyn Error>> {
+    let id: String = STANDARD.decode(id)?.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!("{}{}", DATA_KEYS_PREFIX, id))
+}
+
+/// Store a wrapped data key on its own, unless it already is
+pub async fn record_data_key(wrapped: &WrappedKey) -> Result<(), Box<dyn Error>> {
+    let key = data_key_object(&wrapped.id)?;
+    let result = storage::backend()?.put(&key, &serde_json::to_vec(wrapped)?, false).await;
+    match result {
+        Ok(()) | Err(StorageError::Conflict(_)) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A data key stored by `record_data_key`, if there is one with this ID
+pub async fn fetch_data_key(id: &str) -> Result<Option<WrappedKey>, Box<dyn Error>> {
+    let key = data_key_object(id)?;
+    let result = storage::backend()?.get(&key).await;
+    match result {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(StorageError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Wrap every stored data key again under the current master key, returning how many there were
+pub async fn rewrap_data_keys() -> Result<usize, Box<dyn Error>> {
+    let backend = storage::backend()?;
+    let keys = backend.list(DATA_KEYS_PREFIX).await?;
+    for key in &keys {
+        let wrapped: WrappedKey = serde_json::from_slice(&backend.get(key).await?)?;
+        let rewrapped = encryption::rewrap_keys(&[wrapped])?;
+        backend.put(key, &serde_json::to_vec(&rewrapped[0])?, true).await?;
+    }
+    Ok(keys.len())
+}
+
+// Manifests are encrypted under the master key, since they hold the wrapped data keys
+fn seal_manifest(manifest: &Manifest, compression: &CompressionSettings) -> Result<Vec<u8>, Box<dyn Error>> {
+    let manifest_data = compression::compress(&serde_json::to_vec(manifest)?, compression)?;
+    encryption::encrypt_data(&manifest_data)
+}
+
+/// Hex-encoded SHA-256 of some file contents
+pub fn content_hash(contents: &[u8]) -> String {
+    sha256(contents).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Resolve a uid to a user name, falling back to the number itself
+fn user_name(uid: u32) -> String {
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => user.name,
+        _ => uid.to_string(),
+    }
+}
+
+// Resolve a gid to a group name, falling back to the number itself
+fn group_name(gid: u32) -> String {
+    match Group::from_gid(Gid::from_raw(gid)) {
+        Ok(Some(group)) => group.name,
+        _ => gid.to_string(),
+    }
+}
@@ -0,0 +1,90 @@
+use super::{check_key, io_error, walk_keys, StorageBackend, StorageError, PARTIAL_SUFFIX};
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Objects as files under a local directory, e.g. a USB disk or a NAS mount
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: PathBuf) -> Self {
+        LocalBackend { root }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        Ok(self.root.join(check_key(key)?))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    async fn put(&self, key: &str, data: &[u8], overwrite: bool) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        if !overwrite && fs::try_exists(&path).await.map_err(|e| io_error(key, e))? {
+            return Err(StorageError::Conflict(key.to_string()));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await.map_err(|e| io_error(key, e))?;
+        }
+        let mut partial = path.clone().into_os_string();
+        partial.push(PARTIAL_SUFFIX);
+        fs::write(&partial, data).await.map_err(|e| io_error(key, e))?;
+        fs::rename(&partial, &path).await.map_err(|e| io_error(key, e))
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        fs::read(self.path(key)?).await.map_err(|e| io_error(key, e))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let (root, walk_prefix) = (self.root.clone(), prefix.to_string());
+        let walk = tokio::task::spawn_blocking(move || {
+            walk_keys(&root, &walk_prefix, |directory| read_dir(directory).map_err(|e| io_error(&walk_prefix, e)))
+        });
+        walk.await.map_err(|e| io_error(prefix, std::io::Error::other(e)))?
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        fs::remove_file(self.path(key)?).await.map_err(|e| io_error(key, e))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        fs::try_exists(self.path(key)?).await.map_err(|e| io_error(key, e))
+    }
+}
+
+// Entries of one directory, paired with whether they are directories themselves
+fn read_dir(directory: &Path) -> std::io::Result<Vec<(PathBuf, bool)>> {
+    std::fs::read_dir(directory)?
+        .map(|entry| {
+            let entry = entry?;
+            Ok((entry.path(), entry.file_type()?.is_dir()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn list_walks_only_the_prefix_directory() {
+        let root = crate::storage::use_test_storage().join("local-list");
+        let backend = LocalBackend::new(root.clone());
+        for key in ["snapshots/a/manifest.json", "snapshots/b/manifest.json", "chunks/ab", "keys/kdf.json"] {
+            backend.put(key, b"data", true).await.unwrap();
+        }
+        std::fs::write(root.join("snapshots/c.partial"), b"half").unwrap();
+
+        assert_eq!(
+            backend.list("snapshots/").await.unwrap(),
+            ["snapshots/a/manifest.json", "snapshots/b/manifest.json"]
+        );
+        assert_eq!(backend.list("snapshots/b").await.unwrap(), ["snapshots/b/manifest.json"]);
+        assert_eq!(backend.list("k").await.unwrap(), ["keys/kdf.json"]);
+        assert!(backend.list("missing/").await.unwrap().is_empty());
+        assert_eq!(backend.list("").await.unwrap().len(), 4);
+    }
+}
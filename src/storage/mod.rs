@@ -0,0 +1,189 @@
+use crate::config::get_storage_settings;
+use crate::supabase::SupabaseError;
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use thiserror::Error;
+
+mod local;
+mod s3;
+mod sftp;
+mod supabase;
+
+pub use local::LocalBackend;
+pub use s3::{S3Backend, S3Settings};
+pub use sftp::{SftpBackend, SftpSettings};
+pub use supabase::SupabaseBackend;
+
+/// Where snapshots, chunks and keys are stored, from the `storage` setting
+pub enum StorageSettings {
+    Supabase,
+    Local { path: PathBuf },
+    S3(S3Settings),
+    Sftp(SftpSettings),
+}
+
+/// Why a storage operation failed
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("{0} was not found in storage.")]
+    NotFound(String),
+    #[error("{0} already exists in storage.")]
+    Conflict(String),
+    #[error("Invalid object key: {0}")]
+    InvalidKey(String),
+    #[error("Storage is not configured: {0}")]
+    Config(String),
+    #[error("Failed to access {key}: {source}")]
+    Io { key: String, source: std::io::Error },
+    #[error("S3 returned {status} for {key}: {message}")]
+    S3 { key: String, status: StatusCode, message: String },
+    #[error("SFTP failed: {0}")]
+    Sftp(String),
+    #[error("Could not reach storage: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error(transparent)]
+    Supabase(SupabaseError),
+}
+
+impl From<SupabaseError> for StorageError {
+    fn from(e: SupabaseError) -> Self {
+        match e {
+            SupabaseError::NotFound(key) => StorageError::NotFound(key),
+            SupabaseError::Conflict(key) => StorageError::Conflict(key),
+            e => StorageError::Supabase(e),
+        }
+    }
+}
+
+/// A place to keep objects under slash-separated keys such as `chunks/<hash>` or `keys/kdf.json`.
+/// Every backend stores the same layout, so a backup can be copied from one to another.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Store `data` under `key`. Without `overwrite`, an existing object is a `Conflict`.
+    async fn put(&self, key: &str, data: &[u8], overwrite: bool) -> Result<(), StorageError>;
+
+    /// Read the object under `key`
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError>;
+
+    /// Keys of every object whose key starts with `prefix`
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StorageError>;
+
+    /// Remove the object under `key`
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Whether an object is stored under `key`
+    async fn exists(&self, key: &str) -> Result<bool, StorageError>;
+
+    /// Whether the backend lives in the user's Supabase account, which also keeps a row per snapshot.
+    /// Other backends work without logging in and find snapshots from their manifests.
+    fn needs_login(&self) -> bool {
+        false
+    }
+}
+
+// Objects are written under this suffix and renamed into place, so a crash never leaves half an object
+const PARTIAL_SUFFIX: &str = ".partial";
+
+// Keys are relative paths; anything that could point outside a backend's root directory is refused
+fn check_key(key: &str) -> Result<&Path, StorageError> {
+    let relative = Path::new(key);
+    let valid = !key.is_empty()
+        && !key.ends_with(PARTIAL_SUFFIX)
+        && relative.components().all(|component| matches!(component, Component::Normal(_)));
+    if !valid {
+        return Err(StorageError::InvalidKey(key.to_string()));
+    }
+    Ok(relative)
+}
+
+// A failed file operation on `key`, with a missing file reported as `NotFound`
+fn io_error(key: &str, source: std::io::Error) -> StorageError {
+    if source.kind() == ErrorKind::NotFound {
+        StorageError::NotFound(key.to_string())
+    } else {
+        StorageError::Io { key: key.to_string(), source }
+    }
+}
+
+// Keys of the files under `root` that start with `prefix`, for backends that keep objects as files.
+// `read_dir` lists one directory as paths paired with whether they are directories themselves.
+fn walk_keys(
+    root: &Path,
+    prefix: &str,
+    mut read_dir: impl FnMut(&Path) -> Result<Vec<(PathBuf, bool)>, StorageError>,
+) -> Result<Vec<String>, StorageError> {
+    // Only walk the directory the prefix points into
+    let start = match prefix.rfind('/') {
+        Some(end) => root.join(check_key(&prefix[..end])?),
+        None => root.to_path_buf(),
+    };
+    let mut keys = Vec::new();
+    let mut directories = vec![start];
+    while let Some(directory) = directories.pop() {
+        let entries = match read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(StorageError::NotFound(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        for (path, is_dir) in entries {
+            if is_dir {
+                directories.push(path);
+                continue;
+            }
+            let Some(key) = path.strip_prefix(root).ok().and_then(Path::to_str) else {
+                continue;
+            };
+            if key.starts_with(prefix) && !key.ends_with(PARTIAL_SUFFIX) {
+                keys.push(key.to_string());
+            }
+        }
+    }
+    keys.sort();
+    Ok(keys)
+}
+
+static BACKEND: OnceLock<Box<dyn StorageBackend>> = OnceLock::new();
+
+/// Whether backups need a Supabase session. Settings that can't be read count as needing one,
+/// so the error surfaces when the backend is first used rather than as a missing login.
+pub fn needs_login() -> bool {
+    backend().map_or(true, |backend| backend.needs_login())
+}
+
+/// The backend chosen in the settings, opened on first use
+pub fn backend() -> Result<&'static dyn StorageBackend, StorageError> {
+    if let Some(backend) = BACKEND.get() {
+        return Ok(backend.as_ref());
+    }
+    let settings = get_storage_settings().map_err(|e| StorageError::Config(e.to_string()))?;
+    let backend: Box<dyn StorageBackend> = match settings {
+        StorageSettings::Supabase => Box::new(SupabaseBackend::new()?),
+        StorageSettings::Local { path } => Box::new(LocalBackend::new(path)),
+        StorageSettings::S3(settings) => Box::new(S3Backend::new(settings)?),
+        StorageSettings::Sftp(settings) => Box::new(SftpBackend::new(settings)),
+    };
+    Ok(BACKEND.get_or_init(|| backend).as_ref())
+}
+
+/// Store objects in a temporary directory for the rest of the test process, returning a scratch
+/// directory next to it. Local state such as restore rollbacks and logs goes there too, not into the
+/// home or working directory.
+#[cfg(test)]
+pub fn use_test_storage() -> &'static Path {
+    static ROOT: OnceLock<PathBuf> = OnceLock::new();
+    ROOT.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("continu-test-{}", std::process::id()));
+        std::env::set_var("XDG_DATA_HOME", root.join("data"));
+        std::env::set_var("XDG_CONFIG_HOME", root.join("config"));
+        crate::logging::set_log_dir(root.join("logs"));
+        if BACKEND.set(Box::new(LocalBackend::new(root.join("storage")))).is_err() {
+            panic!("The storage backend was opened before the test storage.");
+        }
+        let scratch = root.join("scratch");
+        std::fs::create_dir_all(&scratch).unwrap();
+        scratch
+    })
+}
@@ -0,0 +1,214 @@
+use super::{check_key, io_error, walk_keys, StorageBackend, StorageError, PARTIAL_SUFFIX};
+use async_trait::async_trait;
+use dotenv::var;
+use ssh2::{CheckResult, KnownHostFileKind, Session, Sftp};
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Give up on a stalled server rather than hang a scheduled backup
+const TIMEOUT_MS: u32 = 30_000;
+
+/// Where the remote directory lives, from the `sftp_*` settings
+pub struct SftpSettings {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub path: PathBuf,             // Remote directory the objects are stored under
+    pub key_file: Option<PathBuf>, // Private key to log in with; without one the SSH agent is asked
+}
+
+/// Objects as files under a directory on an SSH server, in the same layout as the local backend
+pub struct SftpBackend {
+    inner: Arc<Inner>,
+}
+
+// libssh2 is blocking, so operations run on tokio's blocking threads and share one connection
+struct Inner {
+    settings: SftpSettings,
+    connection: Mutex<Option<Sftp>>,
+}
+
+impl SftpBackend {
+    pub fn new(settings: SftpSettings) -> Self {
+        SftpBackend { inner: Arc::new(Inner { settings, connection: Mutex::new(None) }) }
+    }
+
+    async fn run<T, F>(&self, operation: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Inner, &Sftp) -> Result<T, StorageError> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || inner.with_sftp(|sftp| operation(&inner, sftp)))
+            .await
+            .map_err(|e| StorageError::Sftp(e.to_string()))?
+    }
+}
+
+impl Inner {
+    // Run `operation` on the open connection, connecting first if there is none. A connection
+    // that failed for any reason but a missing or existing object is dropped and reopened next time.
+    fn with_sftp<T>(&self, operation: impl FnOnce(&Sftp) -> Result<T, StorageError>) -> Result<T, StorageError> {
+        let mut connection = self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let sftp = match connection.take() {
+            Some(sftp) => sftp,
+            None => self.connect()?,
+        };
+        let result = operation(&sftp);
+        if !matches!(result, Err(StorageError::Io { .. }) | Err(StorageError::Sftp(_))) {
+            *connection = Some(sftp);
+        }
+        result
+    }
+
+    fn connect(&self) -> Result<Sftp, StorageError> {
+        let settings = &self.settings;
+        let tcp = TcpStream::connect((settings.host.as_str(), settings.port)).map_err(|e| self.failed(e))?;
+        let mut session = Session::new().map_err(|e| self.failed(e))?;
+        session.set_timeout(TIMEOUT_MS);
+        session.set_tcp_stream(tcp);
+        session.handshake().map_err(|e| self.failed(e))?;
+        self.verify_host_key(&session)?;
+
+        // `SFTP_KEY_PASSPHRASE` unlocks an encrypted key file without a terminal
+        let authenticated = match &settings.key_file {
+            Some(key_file) => {
+                let passphrase = var("SFTP_KEY_PASSPHRASE").ok();
+                session.userauth_pubkey_file(&settings.user, None, key_file, passphrase.as_deref())
+            }
+            None => session.userauth_agent(&settings.user),
+        };
+        authenticated.map_err(|e| self.failed(format!("Authentication as {} failed: {}", settings.user, e)))?;
+        session.sftp().map_err(|e| self.failed(e))
+    }
+
+    // Only talk to a server whose key is already in ~/.ssh/known_hosts, as `ssh` would
+    fn verify_host_key(&self, session: &Session) -> Result<(), StorageError> {
+        let settings = &self.settings;
+        let (key, _) = session.host_key().ok_or_else(|| self.failed("The server sent no host key."))?;
+        let mut known_hosts = session.known_hosts().map_err(|e| self.failed(e))?;
+        if let Some(home) = dirs::home_dir() {
+            // A missing file just means no host is known yet
+            let _ = known_hosts.read_file(&home.join(".ssh/known_hosts"), KnownHostFileKind::OpenSSH);
+        }
+        match known_hosts.check_port(&settings.host, settings.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(self.failed(
+                "The host key does not match ~/.ssh/known_hosts. Someone may be intercepting the connection.",
+            )),
+            CheckResult::NotFound | CheckResult::Failure => Err(self.failed(
+                "The host is not in ~/.ssh/known_hosts. Connect once with `ssh` to check and add its key.",
+            )),
+        }
+    }
+
+    fn failed(&self, message: impl Display) -> StorageError {
+        StorageError::Sftp(format!("{}: {}", self.settings.host, message))
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        Ok(self.settings.path.join(check_key(key)?))
+    }
+
+    fn put(&self, sftp: &Sftp, key: &str, data: &[u8], overwrite: bool) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        if !overwrite && self.exists(sftp, key)? {
+            return Err(StorageError::Conflict(key.to_string()));
+        }
+        if let Some(parent) = path.parent() {
+            create_dirs(sftp, parent).map_err(|e| io_error(key, e.into()))?;
+        }
+
+        let mut partial = path.clone().into_os_string();
+        partial.push(PARTIAL_SUFFIX);
+        let partial = PathBuf::from(partial);
+        let mut file = sftp.create(&partial).map_err(|e| io_error(key, e.into()))?;
+        file.write_all(data).map_err(|e| io_error(key, e))?;
+        drop(file);
+
+        // SFTP servers refuse to rename over an existing file, so an overwrite removes it first
+        if let Err(e) = sftp.rename(&partial, &path, None) {
+            if !overwrite || sftp.stat(&path).is_err() {
+                return Err(io_error(key, e.into()));
+            }
+            sftp.unlink(&path).map_err(|e| io_error(key, e.into()))?;
+            sftp.rename(&partial, &path, None).map_err(|e| io_error(key, e.into()))?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, sftp: &Sftp, key: &str) -> Result<Vec<u8>, StorageError> {
+        let mut file = sftp.open(self.path(key)?).map_err(|e| io_error(key, e.into()))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|e| io_error(key, e))?;
+        Ok(data)
+    }
+
+    fn list(&self, sftp: &Sftp, prefix: &str) -> Result<Vec<String>, StorageError> {
+        walk_keys(&self.settings.path, prefix, |directory| {
+            let entries = sftp.readdir(directory).map_err(|e| io_error(prefix, e.into()))?;
+            Ok(entries.into_iter().map(|(path, stat)| (path, stat.is_dir())).collect())
+        })
+    }
+
+    fn delete(&self, sftp: &Sftp, key: &str) -> Result<(), StorageError> {
+        sftp.unlink(&self.path(key)?).map_err(|e| io_error(key, e.into()))
+    }
+
+    fn exists(&self, sftp: &Sftp, key: &str) -> Result<bool, StorageError> {
+        match sftp.stat(&self.path(key)?) {
+            Ok(_) => Ok(true),
+            Err(e) => match io_error(key, e.into()) {
+                StorageError::NotFound(_) => Ok(false),
+                e => Err(e),
+            },
+        }
+    }
+}
+
+// Create `directory` and any missing parents. Most objects go into a directory that already
+// exists, so that is checked first.
+fn create_dirs(sftp: &Sftp, directory: &Path) -> Result<(), ssh2::Error> {
+    if sftp.stat(directory).is_ok() {
+        return Ok(());
+    }
+    let mut path = PathBuf::new();
+    for component in directory.components() {
+        path.push(component);
+        if sftp.stat(&path).is_err() {
+            sftp.mkdir(&path, 0o700)?;
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl StorageBackend for SftpBackend {
+    async fn put(&self, key: &str, data: &[u8], overwrite: bool) -> Result<(), StorageError> {
+        let (key, data) = (key.to_string(), data.to_vec());
+        self.run(move |inner, sftp| inner.put(sftp, &key, &data, overwrite)).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let key = key.to_string();
+        self.run(move |inner, sftp| inner.get(sftp, &key)).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let prefix = prefix.to_string();
+        self.run(move |inner, sftp| inner.list(sftp, &prefix)).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let key = key.to_string();
+        self.run(move |inner, sftp| inner.delete(sftp, &key)).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        let key = key.to_string();
+        self.run(move |inner, sftp| inner.exists(sftp, &key)).await
+    }
+}
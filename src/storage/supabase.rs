@@ -0,0 +1,41 @@
+use super::{StorageBackend, StorageError};
+use crate::supabase::{client, SupabaseClient};
+use async_trait::async_trait;
+
+/// Objects in the Supabase Storage bucket, under a folder named after the user's ID
+pub struct SupabaseBackend {
+    client: &'static SupabaseClient,
+}
+
+impl SupabaseBackend {
+    pub fn new() -> Result<Self, StorageError> {
+        Ok(SupabaseBackend { client: client()? })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SupabaseBackend {
+    async fn put(&self, key: &str, data: &[u8], overwrite: bool) -> Result<(), StorageError> {
+        Ok(self.client.upload(key, data, overwrite).await?)
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        Ok(self.client.download(key).await?)
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        Ok(self.client.list(prefix).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        Ok(self.client.delete(key).await?)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        Ok(self.client.exists(key).await?)
+    }
+
+    fn needs_login(&self) -> bool {
+        true
+    }
+}
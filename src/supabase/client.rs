@@ -0,0 +1,386 @@
+use super::SupabaseError;
+use crate::auth;
+use chrono::Utc;
+use dotenv::var;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+// Refresh this long before the access token expires, so a request never goes out with a stale one
+const REFRESH_MARGIN_SECS: i64 = 60;
+
+// Storage returns at most this many entries per list request
+const LIST_PAGE_SIZE: usize = 1000;
+
+// Wait this long after a rate limit that doesn't say when to retry
+const DEFAULT_RETRY_AFTER_SECS: u64 = 60;
+
+static CLIENT: OnceLock<SupabaseClient> = OnceLock::new();
+
+/// The client shared by every request, built from the environment on first use
+pub fn client() -> Result<&'static SupabaseClient, SupabaseError> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = SupabaseClient::new(SupabaseConfig::from_env()?);
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+/// Where the Supabase project lives and the anon key that identifies it
+pub struct SupabaseConfig {
+    pub url: String,
+    pub anon_key: String,
+    pub bucket: String,
+}
+
+impl SupabaseConfig {
+    /// Read `SUPABASE_URL`, `SUPABASE_KEY` and `SUPABASE_BUCKET`
+    pub fn from_env() -> Result<Self, SupabaseError> {
+        Ok(SupabaseConfig {
+            url: var("SUPABASE_URL").map_err(|_| SupabaseError::MissingConfig("SUPABASE_URL"))?,
+            anon_key: var("SUPABASE_KEY").map_err(|_| SupabaseError::MissingConfig("SUPABASE_KEY"))?,
+            bucket: var("SUPABASE_BUCKET").map_err(|_| SupabaseError::MissingConfig("SUPABASE_BUCKET"))?,
+        })
+    }
+}
+
+/// The signed-in user and the tokens their requests are made with
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub access_token: String,
+    pub refresh_token: String, // Exchanged for a new access token shortly before it expires
+    pub expires_at: i64,       // Unix time the access token expires
+    pub email: String,
+    pub user_id: String,    // Store the user ID for later reference
+    pub login_time: String, // Timestamp of login
+}
+
+impl Session {
+    pub fn new(email: &str, tokens: TokenResponse) -> Self {
+        let mut session = Session {
+            access_token: String::new(),
+            refresh_token: String::new(),
+            expires_at: 0,
+            email: email.to_string(),
+            user_id: tokens.user.id.clone(),
+            login_time: Utc::now().to_rfc3339(),
+        };
+        session.set_tokens(tokens);
+        session
+    }
+
+    fn set_tokens(&mut self, tokens: TokenResponse) {
+        self.access_token = tokens.access_token;
+        self.refresh_token = tokens.refresh_token;
+        self.expires_at = Utc::now().timestamp() + tokens.expires_in;
+    }
+}
+
+/// Tokens returned by a password login or a refresh
+#[derive(Deserialize)]
+pub struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    expires_in: i64,
+    user: User,
+}
+
+#[derive(Deserialize)]
+struct User {
+    id: String,
+}
+
+// An object or folder in a storage list response
+#[derive(Deserialize)]
+struct ListEntry {
+    name: String,
+    id: Option<String>,
+}
+
+/// Typed access to Supabase Auth, the REST tables and Storage over one pooled connection
+pub struct SupabaseClient {
+    config: SupabaseConfig,
+    http: Client,
+    session: Mutex<Option<Session>>, // Read from the session file on first use
+    // Only one refresh at a time: Supabase rotates refresh tokens, so a second concurrent refresh
+    // with the same token could be rejected and log the user out
+    refresh_lock: tokio::sync::Mutex<()>,
+}
+
+impl SupabaseClient {
+    pub fn new(config: SupabaseConfig) -> Self {
+        SupabaseClient {
+            config,
+            http: Client::new(),
+            session: Mutex::new(None),
+            refresh_lock: tokio::sync::Mutex::const_new(()),
+        }
+    }
+
+    /// Exchange an email and password for tokens
+    pub async fn sign_in(&self, email: &str, password: &str) -> Result<TokenResponse, SupabaseError> {
+        let response = self
+            .http
+            .post(self.auth_url("token?grant_type=password"))
+            .header("apikey", &self.config.anon_key)
+            .json(&json!({ "email": email, "password": password }))
+            .send()
+            .await?;
+        Ok(check(response, "login").await?.json().await?)
+    }
+
+    /// Register a new user
+    pub async fn sign_up(&self, email: &str, password: &str) -> Result<(), SupabaseError> {
+        let response = self
+            .http
+            .post(self.auth_url("signup"))
+            .header("apikey", &self.config.anon_key)
+            .json(&json!({ "email": email, "password": password }))
+            .send()
+            .await?;
+        check(response, "sign-up").await?;
+        Ok(())
+    }
+
+    /// Send a password reset link
+    pub async fn recover_password(&self, email: &str) -> Result<(), SupabaseError> {
+        let response = self
+            .http
+            .post(self.auth_url("recover"))
+            .header("apikey", &self.config.anon_key)
+            .json(&json!({ "email": email }))
+            .send()
+            .await?;
+        check(response, "password reset").await?;
+        Ok(())
+    }
+
+    /// Make `session` the current one and save it
+    pub fn set_session(&self, session: Session) -> Result<(), SupabaseError> {
+        auth::save_session(&session).map_err(|e| SupabaseError::Session(e.to_string()))?;
+        *self.lock_session() = Some(session);
+        Ok(())
+    }
+
+    /// Forget the session and delete the session file
+    pub fn sign_out(&self) -> Result<(), SupabaseError> {
+        *self.lock_session() = None;
+        auth::clear_session().map_err(|e| SupabaseError::Session(e.to_string()))
+    }
+
+    /// The current session, read from the session file if this process hasn't used it yet.
+    /// A session file that can't be read is deleted, so the user is logged out.
+    pub fn session(&self) -> Result<Session, SupabaseError> {
+        let mut cached = self.lock_session();
+        if let Some(session) = cached.as_ref() {
+            return Ok(session.clone());
+        }
+        if !auth::is_logged_in() {
+            return Err(SupabaseError::NotLoggedIn);
+        }
+        match auth::load_session() {
+            Ok(session) => Ok(cached.insert(session).clone()),
+            Err(_) => {
+                drop(cached);
+                self.sign_out()?;
+                Err(SupabaseError::AuthExpired)
+            }
+        }
+    }
+
+    /// ID of the logged-in user, which rows and objects are scoped to
+    pub fn user_id(&self) -> Result<String, SupabaseError> {
+        Ok(self.session()?.user_id)
+    }
+
+    /// The access token of the session, refreshed first if it is about to expire.
+    /// A refresh token the server rejects logs the user out.
+    pub async fn access_token(&self) -> Result<String, SupabaseError> {
+        let _guard = self.refresh_lock.lock().await;
+        let mut session = self.session()?;
+        if session.expires_at - REFRESH_MARGIN_SECS > Utc::now().timestamp() {
+            return Ok(session.access_token);
+        }
+
+        let response = self
+            .http
+            .post(self.auth_url("token?grant_type=refresh_token"))
+            .header("apikey", &self.config.anon_key)
+            .json(&json!({ "refresh_token": session.refresh_token }))
+            .send()
+            .await?;
+
+        // The refresh token was revoked or has expired as well; only a new login helps.
+        // Server trouble and rate limits keep the session for the next attempt.
+        let status = response.status();
+        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            self.sign_out()?;
+            return Err(SupabaseError::AuthExpired);
+        }
+        session.set_tokens(check(response, "session refresh").await?.json().await?);
+        self.set_session(session.clone())?;
+        Ok(session.access_token)
+    }
+
+    /// Rows of `table` matching a PostgREST query such as `user_id=eq.<id>&order=backup_date.desc`
+    pub async fn select<T: DeserializeOwned>(&self, table: &str, query: &str) -> Result<Vec<T>, SupabaseError> {
+        let url = format!("{}?{}", self.rest_url(table), query);
+        let request = self.as_user(self.http.get(&url)).await?;
+        let response = request.send().await?;
+        Ok(check(response, table).await?.json().await?)
+    }
+
+    /// Insert one row into `table`
+    pub async fn insert<T: Serialize>(&self, table: &str, row: &T) -> Result<(), SupabaseError> {
+        let request = self.as_user(self.http.post(self.rest_url(table))).await?;
+        let response = request.json(row).send().await?;
+        check(response, table).await?;
+        Ok(())
+    }
+
+    /// Store an object under the user's folder. Without `upsert`, an existing object is a `Conflict`.
+    pub async fn upload(&self, object_key: &str, data: &[u8], upsert: bool) -> Result<(), SupabaseError> {
+        let request = self.as_user(self.http.post(self.object_url(object_key)?)).await?;
+        let response = request
+            .header("x-upsert", upsert.to_string())
+            .body(data.to_vec())
+            .send()
+            .await?;
+        check(response, object_key).await?;
+        Ok(())
+    }
+
+    /// Read an object from the user's folder
+    pub async fn download(&self, object_key: &str) -> Result<Vec<u8>, SupabaseError> {
+        let request = self.as_user(self.http.get(self.object_url(object_key)?)).await?;
+        let response = check(request.send().await?, object_key).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Whether an object exists in the user's folder
+    pub async fn exists(&self, object_key: &str) -> Result<bool, SupabaseError> {
+        let request = self.as_user(self.http.head(self.object_url(object_key)?)).await?;
+        match check(request.send().await?, object_key).await {
+            Ok(_) => Ok(true),
+            // A HEAD response has no body to tell a missing object from a bad request
+            Err(SupabaseError::NotFound(_)) => Ok(false),
+            Err(SupabaseError::Api { status: StatusCode::BAD_REQUEST, .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Keys of the objects in the user's folder that start with `prefix`
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, SupabaseError> {
+        let user_id = self.user_id()?;
+        let url = format!("{}/storage/v1/object/list/{}", self.config.url, self.config.bucket);
+        let (folder, search) = match prefix.rfind('/') {
+            Some(end) => (&prefix[..end], &prefix[end + 1..]),
+            None => ("", prefix),
+        };
+
+        // Storage lists one folder at a time, so subfolders are walked one by one
+        let mut keys = Vec::new();
+        let mut folders = vec![(folder.to_string(), search.to_string())];
+        while let Some((folder, search)) = folders.pop() {
+            let storage_prefix = if folder.is_empty() { user_id.clone() } else { format!("{}/{}", user_id, folder) };
+            let mut offset = 0;
+            loop {
+                let request = self.as_user(self.http.post(&url)).await?;
+                let response = request
+                    .json(&json!({ "prefix": storage_prefix, "search": search, "limit": LIST_PAGE_SIZE, "offset": offset }))
+                    .send()
+                    .await?;
+                let entries: Vec<ListEntry> = check(response, prefix).await?.json().await?;
+                for entry in &entries {
+                    let key = if folder.is_empty() { entry.name.clone() } else { format!("{}/{}", folder, entry.name) };
+                    match entry.id {
+                        Some(_) => keys.push(key),
+                        None => folders.push((key, String::new())), // Folders have no ID
+                    }
+                }
+                if entries.len() < LIST_PAGE_SIZE {
+                    break;
+                }
+                offset += LIST_PAGE_SIZE;
+            }
+        }
+        // The search is case-insensitive, keys are not
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Remove an object from the user's folder
+    pub async fn delete(&self, object_key: &str) -> Result<(), SupabaseError> {
+        let request = self.as_user(self.http.delete(self.object_url(object_key)?)).await?;
+        check(request.send().await?, object_key).await?;
+        Ok(())
+    }
+
+    // The anon key identifies the project, and the user's JWT lets row-level security decide
+    // which rows and objects the request may touch
+    async fn as_user(&self, request: RequestBuilder) -> Result<RequestBuilder, SupabaseError> {
+        let token = self.access_token().await?;
+        Ok(request.header("apikey", &self.config.anon_key).bearer_auth(token))
+    }
+
+    fn auth_url(&self, path: &str) -> String {
+        format!("{}/auth/v1/{}", self.config.url, path)
+    }
+
+    fn rest_url(&self, table: &str) -> String {
+        format!("{}/rest/v1/{}", self.config.url, table)
+    }
+
+    // Every object lives under a folder named after the user's ID, which storage policies match on
+    fn object_url(&self, object_key: &str) -> Result<String, SupabaseError> {
+        Ok(format!("{}/storage/v1/object/{}/{}/{}", self.config.url, self.config.bucket, self.user_id()?, object_key))
+    }
+
+    // The session survives a poisoned lock; it is only ever replaced whole
+    fn lock_session(&self) -> MutexGuard<'_, Option<Session>> {
+        self.session.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// Turn an error response into the matching `SupabaseError`. Storage answers 400 for some
+// missing or duplicate objects and puts the real status in the body, so that is consulted too.
+async fn check(response: Response, what: &str) -> Result<Response, SupabaseError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_RETRY_AFTER_SECS);
+    let message = response.text().await.unwrap_or_default();
+    let status = match status {
+        StatusCode::BAD_REQUEST => body_status(&message).unwrap_or(status),
+        _ => status,
+    };
+
+    Err(match status {
+        StatusCode::UNAUTHORIZED => SupabaseError::AuthExpired,
+        StatusCode::NOT_FOUND => SupabaseError::NotFound(what.to_string()),
+        StatusCode::CONFLICT => SupabaseError::Conflict(what.to_string()),
+        StatusCode::TOO_MANY_REQUESTS => SupabaseError::RateLimited { retry_after },
+        _ => SupabaseError::Api { what: what.to_string(), status, message },
+    })
+}
+
+// The `statusCode` Storage reports in an error body, as a string or a number
+fn body_status(message: &str) -> Option<StatusCode> {
+    let body: serde_json::Value = serde_json::from_str(message).ok()?;
+    let code = match &body["statusCode"] {
+        serde_json::Value::String(code) => code.parse().ok()?,
+        serde_json::Value::Number(code) => u16::try_from(code.as_u64()?).ok()?,
+        _ => return None,
+    };
+    StatusCode::from_u16(code).ok()
+}
@@ -0,0 +1,52 @@
+use reqwest::StatusCode;
+use thiserror::Error;
+
+/// Why a Supabase request failed, so callers can tell a missing object from an outage
+#[derive(Debug, Error)]
+pub enum SupabaseError {
+    #[error("{0} is not set.")]
+    MissingConfig(&'static str),
+    #[error("Please log in first.")]
+    NotLoggedIn,
+    #[error("Your session has expired. Please log in again.")]
+    AuthExpired,
+    #[error("{0} already exists.")]
+    Conflict(String),
+    #[error("{0} was not found.")]
+    NotFound(String),
+    #[error("Too many requests to Supabase. Try again in {retry_after} seconds.")]
+    RateLimited { retry_after: u64 },
+    #[error("Could not reach Supabase: {0}")]
+    Network(reqwest::Error),
+    #[error("Unexpected response from Supabase: {0}")]
+    InvalidResponse(String),
+    #[error("Supabase returned {status} for {what}: {message}")]
+    Api { what: String, status: StatusCode, message: String },
+    #[error("Failed to access the session file: {0}")]
+    Session(String),
+}
+
+impl SupabaseError {
+    /// Whether the request certainly had no effect, because it was never sent or the server turned it down
+    pub fn is_refused(&self) -> bool {
+        match self {
+            SupabaseError::MissingConfig(_)
+            | SupabaseError::NotLoggedIn
+            | SupabaseError::AuthExpired
+            | SupabaseError::RateLimited { .. }
+            | SupabaseError::Session(_) => true,
+            SupabaseError::Api { status, .. } => status.is_client_error(),
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for SupabaseError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            SupabaseError::InvalidResponse(e.to_string())
+        } else {
+            SupabaseError::Network(e)
+        }
+    }
+}